@@ -0,0 +1,185 @@
+use rayon::prelude::*;
+
+use super::Element;
+use super::simd;
+use super::simple::Matrix;
+
+/// Shape of each product in a batch, `m`×`k` times `k`×`n`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Shape {
+    pub m: usize,
+    pub k: usize,
+    pub n: usize,
+}
+
+/// Row-major matrices stored one after another, `stride` elements apart.
+///
+/// A stride of 0 uses the same matrix for all the products.
+#[derive(Clone, Copy, Debug)]
+pub struct Batch<'a> {
+    pub content: &'a [Element],
+    pub stride: usize,
+}
+
+impl<'a> Batch<'a> {
+    fn get(&self, idx: usize, len: usize) -> &'a [Element] {
+        let content = self.content;
+        &content[idx * self.stride .. idx * self.stride + len]
+    }
+}
+
+// Number of products in one rayon task. Small products are cheap and handing them out one by one
+// would be dominated by the overhead.
+const CHUNK: usize = 16;
+
+// The kernel for the small matrices. It doesn't allocate and builds each row of the result from
+// the SIMD-scaled rows of b, all continuous in memory.
+fn kernel(r: &mut [Element], a: &[Element], b: &[Element], shape: Shape) {
+    let Shape { m, k, n } = shape;
+    for val in r.iter_mut() {
+        *val = 0.;
+    }
+    simd::multiply_add_strided(r, n, a, k, b, n, (m, k, n));
+}
+
+/// Computes `count` products, storing them one after another into `into`.
+pub fn multiply_into(shape: Shape, count: usize, a: Batch, b: Batch, into: &mut [Element]) {
+    let out = shape.m * shape.n;
+    assert_eq!(into.len(), count * out);
+    if count == 0 {
+        return;
+    }
+    // Checked once here, so the slicing in the tasks can't panic half way through
+    assert!(a.content.len() >= (count - 1) * a.stride + shape.m * shape.k);
+    assert!(b.content.len() >= (count - 1) * b.stride + shape.k * shape.n);
+    if out == 0 {
+        return;
+    }
+
+    into.par_chunks_mut(out * CHUNK)
+        .enumerate()
+        .for_each(|(chunk_idx, chunk)| {
+            for (i, r) in chunk.chunks_mut(out).enumerate() {
+                let idx = chunk_idx * CHUNK + i;
+                let a = a.get(idx, shape.m * shape.k);
+                let b = b.get(idx, shape.k * shape.n);
+                kernel(r, a, b, shape);
+            }
+        });
+}
+
+pub fn multiply(shape: Shape, count: usize, a: Batch, b: Batch) -> Vec<Element> {
+    let mut result = vec![0.; count * shape.m * shape.n];
+    multiply_into(shape, count, a, b, &mut result);
+    result
+}
+
+/// Multiplies pairs of matrices of the same shapes, `a[i] * b[i]`.
+///
+/// The results are stored one after another into `into`.
+pub fn multiply_matrices_into(a: &[Matrix], b: &[Matrix], into: &mut [Element]) {
+    assert_eq!(a.len(), b.len());
+    if a.is_empty() {
+        return;
+    }
+    let shape = Shape {
+        m: a[0].height(),
+        k: a[0].width(),
+        n: b[0].width(),
+    };
+    for (a, b) in a.iter().zip(b) {
+        assert_eq!((a.height(), a.width()), (shape.m, shape.k));
+        assert_eq!((b.height(), b.width()), (shape.k, shape.n));
+    }
+    let out = shape.m * shape.n;
+    assert_eq!(into.len(), a.len() * out);
+    if out == 0 {
+        return;
+    }
+
+    into.par_chunks_mut(out * CHUNK)
+        .zip(a.par_chunks(CHUNK))
+        .zip(b.par_chunks(CHUNK))
+        .for_each(|((chunk, a), b)| {
+            for ((r, a), b) in chunk.chunks_mut(out).zip(a).zip(b) {
+                kernel(r, a.slice().content, b.slice().content, shape);
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ::simple;
+
+    fn check(shape: Shape, count: usize, broadcast: bool) {
+        let a = (0..count)
+            .map(|_| Matrix::random(shape.k, shape.m))
+            .collect::<Vec<_>>();
+        let b = (0..count)
+            .map(|_| Matrix::random(shape.n, shape.k))
+            .collect::<Vec<_>>();
+        let a_content = a.iter()
+            .flat_map(|m| m.slice().content.iter().cloned())
+            .collect::<Vec<_>>();
+        let b_content = b.iter()
+            .flat_map(|m| m.slice().content.iter().cloned())
+            .collect::<Vec<_>>();
+        let a_batch = Batch {
+            content: &a_content,
+            stride: shape.m * shape.k,
+        };
+        let b_batch = Batch {
+            content: &b_content,
+            stride: if broadcast { 0 } else { shape.k * shape.n },
+        };
+        let result = multiply(shape, count, a_batch, b_batch);
+
+        let mut pairs_result = vec![0.; result.len()];
+        if !broadcast {
+            multiply_matrices_into(&a, &b, &mut pairs_result);
+        }
+
+        for i in 0..count {
+            let b = if broadcast { &b[0] } else { &b[i] };
+            let expected = simple::multiply(&a[i], b);
+            let out = shape.m * shape.n;
+            let mut got = Matrix::sized(shape.n, shape.m);
+            got.slice_mut().content.copy_from_slice(&result[i * out .. (i + 1) * out]);
+            got.assert_approx(&expected);
+            if !broadcast {
+                got.slice_mut().content.copy_from_slice(&pairs_result[i * out .. (i + 1) * out]);
+                got.assert_approx(&expected);
+            }
+        }
+    }
+
+    #[test]
+    fn square() {
+        for &size in &[1, 3, 16, 32] {
+            check(Shape { m: size, k: size, n: size }, 100, false);
+        }
+    }
+
+    #[test]
+    fn rect() {
+        check(Shape { m: 3, k: 7, n: 5 }, 37, false);
+        check(Shape { m: 16, k: 1, n: 2 }, 17, false);
+    }
+
+    #[test]
+    fn broadcast() {
+        check(Shape { m: 8, k: 8, n: 8 }, 50, true);
+    }
+
+    #[test]
+    fn empty() {
+        let content: [Element; 0] = [];
+        let batch = Batch {
+            content: &content,
+            stride: 0,
+        };
+        assert!(multiply(Shape { m: 2, k: 2, n: 2 }, 0, batch, batch).is_empty());
+    }
+}
@@ -16,12 +16,18 @@ use failure::Error;
 use structopt::StructOpt;
 use typenum::{U1, U2, U4, U8, U16, U32, U64, U128, U256, U512, U1024, Unsigned};
 
+use fastmatmult::bitmat::BitMatrix;
+use fastmatmult::numa::Pinning;
 use fastmatmult::simple::Matrix;
+use fastmatmult::tiled::{self, Tiles};
 use fastmatmult::znot::{
-    Distribute, DontDistribute, FragMultiplyAdd, Matrix as ZMat, RayonDistribute, SimdMultiplyAdd,
-    SimpleMultiplyAdd
+    CompensatedMultiplyAdd, Cutoff, Distribute, DontDistribute, FragMultiplyAdd, Matrix as ZMat,
+    MixedMultiplyAdd, RayonDistribute, SimdMultiplyAdd, SimpleMultiplyAdd, ThreadBudgetDistribute
 };
 
+// Memory for the temporary buffers of the eight-way recursion.
+const EIGHT_MEMORY: usize = 256 << 20;
+
 #[derive(Debug, StructOpt)]
 struct Opts {
     #[structopt(parse(from_os_str))]
@@ -38,6 +44,19 @@ struct Opts {
     /// Run only the simple multiplication.
     #[structopt(short = "s", long = "simple-only")]
     simple_only: bool,
+
+    /// Additionally try the parallel SIMD multiplication with this parallelism cutoff.
+    ///
+    /// May be given multiple times to sweep over several cutoffs.
+    #[structopt(long = "cutoff")]
+    cutoffs: Vec<usize>,
+
+    /// Pin the worker threads to CPUs: off, cores (skipping the SMT siblings) or threads.
+    ///
+    /// Applies to everything using the global rayon thread pool and to the threads of the
+    /// budget-limited runs.
+    #[structopt(long = "pin", default_value = "off")]
+    pin: Pinning,
 }
 
 fn measure<N: Display, R, F: FnOnce() -> R>(name: N, f: F) -> R {
@@ -49,7 +68,13 @@ fn measure<N: Display, R, F: FnOnce() -> R>(name: N, f: F) -> R {
     result
 }
 
-fn block_inner<Dist, Mult, Frag>(suffix: &str, a: &Matrix, b: &Matrix, expected: Option<&Matrix>)
+fn block_inner<Dist, Mult, Frag>(
+    dist: &Dist,
+    suffix: &str,
+    a: &Matrix,
+    b: &Matrix,
+    expected: Option<&Matrix>,
+)
 where
     Dist: Distribute,
     Frag: Unsigned + Default,
@@ -59,7 +84,7 @@ where
         let a_z = ZMat::<Frag>::from(a);
         let b_z = ZMat::<Frag>::from(b);
         let r_z = measure(format!("recursive-inner{}-{}", suffix, Frag::USIZE), || {
-            fastmatmult::znot::multiply::<_, Dist, Mult>(&a_z, &b_z)
+            fastmatmult::znot::multiply::<_, _, Mult>(dist, &a_z, &b_z)
         });
         Matrix::from(&r_z)
     });
@@ -69,44 +94,118 @@ where
     }
 }
 
-fn block<Frag>(a: &Matrix, b: &Matrix, expected: Option<&Matrix>, cheap: bool)
+fn block<Frag>(
+    a: &Matrix,
+    b: &Matrix,
+    expected: Option<&Matrix>,
+    cheap: bool,
+    cutoffs: &[usize],
+    budget: &ThreadBudgetDistribute<U256>,
+)
 where
-    Frag: Unsigned + Default,
+    Frag: Unsigned + Default + Sync,
 {
     if a.width() < Frag::USIZE {
         return;
     }
+    let paral = RayonDistribute(Frag::default());
+    let paral_cutoff = RayonDistribute(U256::new());
     if !cheap {
-        block_inner::<DontDistribute, SimpleMultiplyAdd, Frag>("", a, b, expected);
-        block_inner::<RayonDistribute<Frag>, SimpleMultiplyAdd, Frag>("-paral", a, b, expected);
+        block_inner::<_, SimpleMultiplyAdd, Frag>(&DontDistribute, "", a, b, expected);
+        block_inner::<_, SimpleMultiplyAdd, Frag>(&paral, "-paral", a, b, expected);
     }
-    block_inner::<RayonDistribute<U256>, SimpleMultiplyAdd, Frag>("-paral-cutoff", a, b, expected);
+    block_inner::<_, SimpleMultiplyAdd, Frag>(&paral_cutoff, "-paral-cutoff", a, b, expected);
     if Frag::USIZE >= 4 {
         if !cheap {
-            block_inner::<DontDistribute, SimdMultiplyAdd, Frag>("-simd", a, b, None);
-            block_inner::<RayonDistribute<Frag>, SimdMultiplyAdd, Frag>("-simd-paral", a, b, None);
+            block_inner::<_, SimdMultiplyAdd, Frag>(&DontDistribute, "-simd", a, b, None);
+            block_inner::<_, SimdMultiplyAdd, Frag>(&paral, "-simd-paral", a, b, None);
         }
-        block_inner::<RayonDistribute<U256>, SimdMultiplyAdd, Frag>(
+        block_inner::<_, SimdMultiplyAdd, Frag>(
+            &paral_cutoff,
             "-simd-paral-cutoff",
             a,
             b,
             None
         );
+        for &cutoff in cutoffs {
+            let suffix = format!("-simd-paral-cutoff{}", cutoff);
+            let dist = RayonDistribute(Cutoff(cutoff));
+            block_inner::<_, SimdMultiplyAdd, Frag>(&dist, &suffix, a, b, None);
+        }
+        block_inner::<_, SimdMultiplyAdd, Frag>(
+            budget,
+            "-simd-budget-cutoff",
+            a,
+            b,
+            None
+        );
+        let a_z = ZMat::<Frag>::from(a);
+        let b_z = ZMat::<Frag>::from(b);
+        measure(format!("recursive-inner-simd-eight-cutoff-{}", Frag::USIZE), || {
+            fastmatmult::znot::multiply_eight::<_, _, SimdMultiplyAdd>(
+                &paral_cutoff,
+                &a_z,
+                &b_z,
+                EIGHT_MEMORY,
+            )
+        });
+        block_inner::<_, CompensatedMultiplyAdd, Frag>(
+            &paral_cutoff,
+            "-compensated-paral-cutoff",
+            a,
+            b,
+            None
+        );
+        block_inner::<_, MixedMultiplyAdd, Frag>(
+            &paral_cutoff,
+            "-mixed-paral-cutoff",
+            a,
+            b,
+            None
+        );
+        measure(format!("recursive-inner-compensated-split-paral-cutoff-{}", Frag::USIZE), || {
+            fastmatmult::znot::multiply_compensated(&paral_cutoff, &a_z, &b_z)
+        });
+        measure(format!("recursive-inner-mixed-wide-paral-cutoff-{}", Frag::USIZE), || {
+            fastmatmult::znot::multiply_mixed(&paral_cutoff, &a_z, &b_z)
+        });
     }
     if Frag::USIZE >= 32 {
         measure(format!("strassen-{}", Frag::USIZE), || {
             let a_z = ZMat::<Frag>::from(a);
             let b_z = ZMat::<Frag>::from(b);
             let r_z = measure(format!("strassen-inner-{}", Frag::USIZE), || {
-                fastmatmult::znot::strassen::<_, RayonDistribute<Frag>, SimdMultiplyAdd>(&a_z, &b_z)
+                fastmatmult::znot::strassen::<_, _, SimdMultiplyAdd>(&paral, &a_z, &b_z)
             });
             Matrix::from(&r_z)
         });
     }
+    if Frag::USIZE >= 8 {
+        for &cutoff in &[128, 256, 512] {
+            if cutoff <= Frag::USIZE || cutoff >= a.width() {
+                continue;
+            }
+            measure(format!("hybrid-strassen-{}-{}", Frag::USIZE, cutoff), || {
+                let a_z = ZMat::<Frag>::from(a);
+                let b_z = ZMat::<Frag>::from(b);
+                let r_z = measure(format!("hybrid-strassen-inner-{}-{}", Frag::USIZE, cutoff), || {
+                    fastmatmult::znot::hybrid_strassen::<_, _, SimdMultiplyAdd>(
+                        &paral_cutoff,
+                        &a_z,
+                        &b_z,
+                        cutoff,
+                    )
+                });
+                Matrix::from(&r_z)
+            });
+        }
+    }
 }
 
 fn run() -> Result<(), Error> {
     let opts = Opts::from_args();
+    opts.pin.install_global()?;
+    let budget = ThreadBudgetDistribute::with_pinning(16, U256::new(), opts.pin)?;
     let m1 = Matrix::load(&opts.input1)?;
     let m2 = Matrix::load(&opts.input2)?;
 
@@ -119,6 +218,10 @@ fn run() -> Result<(), Error> {
         }
         let col_cp = measure("colcp", || fastmatmult::simple::multiply_col_cp(&m1, &m2));
         assert_eq!(simple, col_cp);
+        let simple_paral = measure("simple-paral", || {
+            fastmatmult::simple::multiply_paral(&RayonDistribute(U1::new()), &m1, &m2)
+        });
+        assert_eq!(simple, simple_paral);
         Some(simple)
     };
     let simple = simple.as_ref();
@@ -127,19 +230,67 @@ fn run() -> Result<(), Error> {
     // Not checking equality, because simd does slightly different results due to reordering of the
     // summing
 
+    measure("simd-paral", || {
+        fastmatmult::simd::multiply_paral(&RayonDistribute(U1::new()), &m1, &m2)
+    });
+
+    measure("simd-compensated", || fastmatmult::simd::multiply_compensated(&m1, &m2));
+
+    measure("simd-mixed", || fastmatmult::simd::multiply_mixed(&m1, &m2));
+
+    // Random elements are from 0 to 10, so this is about half of them
+    let bits1 = BitMatrix::from_dense(&m1, 5.);
+    let bits2 = BitMatrix::from_dense(&m2, 5.);
+    let bits = measure("bitmat-paral", || {
+        fastmatmult::bitmat::multiply(&RayonDistribute(U1::new()), &bits1, &bits2)
+    });
+    let russians = measure("bitmat-russians-paral", || {
+        fastmatmult::bitmat::multiply_russians(&RayonDistribute(U1::new()), &bits1, &bits2)
+    });
+    assert_eq!(bits, russians);
+
+    let paral_cutoff = RayonDistribute(U256::new());
+
+    measure("strassen-peel", || {
+        fastmatmult::strassen::multiply(&paral_cutoff, &m1, &m2, 128)
+    });
+
+    for &(i, j, k) in &[(16, 16, 256), (32, 32, 256), (64, 64, 512), (128, 128, 128)] {
+        let tiles = Tiles { i, j, k };
+        if !opts.cheap {
+            measure(format!("tiled-{}x{}x{}", i, j, k), || {
+                tiled::multiply(&DontDistribute, &m1, &m2, tiles)
+            });
+        }
+        measure(format!("tiled-paral-{}x{}x{}", i, j, k), || {
+            tiled::multiply(&RayonDistribute(U1::new()), &m1, &m2, tiles)
+        });
+    }
+
+    for &limit in &[32, 64, 128] {
+        measure(format!("rowmajor-recursive-simd-paral-{}", limit), || {
+            fastmatmult::recursive::multiply::<_, SimdMultiplyAdd>(
+                &paral_cutoff,
+                &m1,
+                &m2,
+                limit,
+            )
+        });
+    }
+
     if !opts.cheap {
-        block::<U1>(&m1, &m2, simple, opts.cheap);
-        block::<U2>(&m1, &m2, simple, opts.cheap);
-        block::<U4>(&m1, &m2, simple, opts.cheap);
-        block::<U8>(&m1, &m2, simple, opts.cheap);
-        block::<U16>(&m1, &m2, simple, opts.cheap);
-        block::<U32>(&m1, &m2, simple, opts.cheap);
+        block::<U1>(&m1, &m2, simple, opts.cheap, &opts.cutoffs, &budget);
+        block::<U2>(&m1, &m2, simple, opts.cheap, &opts.cutoffs, &budget);
+        block::<U4>(&m1, &m2, simple, opts.cheap, &opts.cutoffs, &budget);
+        block::<U8>(&m1, &m2, simple, opts.cheap, &opts.cutoffs, &budget);
+        block::<U16>(&m1, &m2, simple, opts.cheap, &opts.cutoffs, &budget);
+        block::<U32>(&m1, &m2, simple, opts.cheap, &opts.cutoffs, &budget);
     }
-    block::<U64>(&m1, &m2, simple, opts.cheap);
-    block::<U128>(&m1, &m2, simple, opts.cheap);
-    block::<U256>(&m1, &m2, simple, opts.cheap);
-    block::<U512>(&m1, &m2, simple, opts.cheap);
-    block::<U1024>(&m1, &m2, simple, opts.cheap);
+    block::<U64>(&m1, &m2, simple, opts.cheap, &opts.cutoffs, &budget);
+    block::<U128>(&m1, &m2, simple, opts.cheap, &opts.cutoffs, &budget);
+    block::<U256>(&m1, &m2, simple, opts.cheap, &opts.cutoffs, &budget);
+    block::<U512>(&m1, &m2, simple, opts.cheap, &opts.cutoffs, &budget);
+    block::<U1024>(&m1, &m2, simple, opts.cheap, &opts.cutoffs, &budget);
 
     Ok(())
 }
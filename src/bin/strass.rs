@@ -54,7 +54,8 @@ fn run() -> Result<(), Error> {
     measure("strassen-256", || {
         let a_z = ZMat::<U256>::from(&m1);
         let b_z = ZMat::<U256>::from(&m2);
-        let r_z = fastmatmult::znot::strassen::<_, RayonDistribute<U256>, SimdMultiplyAdd>(&a_z, &b_z);
+        let dist = RayonDistribute(U256::new());
+        let r_z = fastmatmult::znot::strassen::<_, _, SimdMultiplyAdd>(&dist, &a_z, &b_z);
         Matrix::from(&r_z)
     });
 
@@ -0,0 +1,275 @@
+use super::Element;
+use super::simple::Matrix;
+use super::znot::Distribute;
+
+const BITS: usize = 64;
+// Rows of the result in one task.
+const BAND: usize = 64;
+// How many rows of b go into one table of the Four Russians method.
+const GROUP: usize = 8;
+// Words of the Four Russians tables built at once (32 MiB).
+const TABLES: usize = 1 << 22;
+
+fn words(width: usize) -> usize {
+    (width + BITS - 1) / BITS
+}
+
+/// A boolean matrix with each row packed into u64 words.
+///
+/// The bits past the width in the last word of each row are always zero.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BitMatrix {
+    width: usize,
+    height: usize,
+    // Words per row
+    stride: usize,
+    content: Vec<u64>,
+}
+
+impl BitMatrix {
+    pub fn sized(w: usize, h: usize) -> Self {
+        let stride = words(w);
+        Self {
+            width: w,
+            height: h,
+            stride,
+            content: vec![0; stride * h],
+        }
+    }
+    pub fn identity(size: usize) -> Self {
+        let mut result = Self::sized(size, size);
+        for i in 0..size {
+            result.set(i, i, true);
+        }
+        result
+    }
+    /// Elements with the absolute value above `threshold` are set.
+    pub fn from_dense(matrix: &Matrix, threshold: Element) -> Self {
+        let mut result = Self::sized(matrix.width(), matrix.height());
+        for (y, row) in matrix.rows().enumerate() {
+            for (x, val) in row.iter().enumerate() {
+                if val.abs() > threshold {
+                    result.set(x, y, true);
+                }
+            }
+        }
+        result
+    }
+    /// Converts to a matrix of ones and zeros.
+    pub fn to_dense(&self) -> Matrix {
+        let mut result = Matrix::sized(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.get(x, y) {
+                    result[(x, y)] = 1.;
+                }
+            }
+        }
+        result
+    }
+    pub fn height(&self) -> usize { self.height }
+    pub fn width(&self) -> usize { self.width }
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        assert!(x < self.width && y < self.height);
+        self.content[y * self.stride + x / BITS] & (1 << (x % BITS)) != 0
+    }
+    pub fn set(&mut self, x: usize, y: usize, val: bool) {
+        assert!(x < self.width && y < self.height);
+        let word = &mut self.content[y * self.stride + x / BITS];
+        let bit = 1 << (x % BITS);
+        if val {
+            *word |= bit;
+        } else {
+            *word &= !bit;
+        }
+    }
+    /// The number of set elements.
+    pub fn count_ones(&self) -> usize {
+        self.content.iter().map(|w| w.count_ones() as usize).sum()
+    }
+    fn row(&self, y: usize) -> &[u64] {
+        &self.content[y * self.stride .. (y + 1) * self.stride]
+    }
+}
+
+fn or_into(r: &mut [u64], row: &[u64]) {
+    for (r, w) in r.iter_mut().zip(row) {
+        *r |= *w;
+    }
+}
+
+// The result rows get the rows of b for each set bit of the a rows.
+fn band(r: &mut [u64], a: &[u64], b: &BitMatrix) {
+    let r_rows = r.chunks_mut(b.stride);
+    for (r_row, a_row) in r_rows.zip(a.chunks(words(b.height))) {
+        for (i, &word) in a_row.iter().enumerate() {
+            let mut word = word;
+            while word != 0 {
+                let bit = word.trailing_zeros() as usize;
+                word &= word - 1;
+                or_into(r_row, b.row(i * BITS + bit));
+            }
+        }
+    }
+}
+
+fn result_for(a: &BitMatrix, b: &BitMatrix) -> BitMatrix {
+    assert_eq!(a.width, b.height);
+    BitMatrix::sized(b.width, a.height)
+}
+
+/// The boolean product (`or` of `and`s).
+///
+/// Bands of the result rows are handed to the `Dist`.
+pub fn multiply<Dist: Distribute>(dist: &Dist, a: &BitMatrix, b: &BitMatrix) -> BitMatrix {
+    let mut result = result_for(a, b);
+    if result.stride == 0 || a.stride == 0 {
+        return result;
+    }
+
+    let mut tasks = result.content
+        .chunks_mut(BAND * b.stride)
+        .zip(a.content.chunks(BAND * a.stride))
+        .collect::<Vec<_>>();
+    dist.run(a.height, &mut tasks, |&mut (ref mut r, a)| band(r, a, b));
+
+    result
+}
+
+// One table of the Four Russians method: all the combinations of the rows of b in the group.
+fn group_table(table: &mut [u64], b: &BitMatrix, group: usize) {
+    let stride = b.stride;
+    let first = group * GROUP;
+    // Each combination is some smaller one plus a single row.
+    for mask in 1..1 << GROUP {
+        let bit = (mask as u32).trailing_zeros() as usize;
+        let (done, rest) = table.split_at_mut(mask * stride);
+        let entry = &mut rest[..stride];
+        let smaller = mask & (mask - 1);
+        entry.copy_from_slice(&done[smaller * stride .. (smaller + 1) * stride]);
+        if first + bit < b.height {
+            or_into(entry, b.row(first + bit));
+        }
+    }
+}
+
+/// The boolean product by the method of Four Russians.
+///
+/// The rows of b are taken by groups of 8 and all 256 of their possible combinations are
+/// precomputed. Then each result row needs only one lookup per group, no matter how dense a is.
+pub fn multiply_russians<Dist>(dist: &Dist, a: &BitMatrix, b: &BitMatrix) -> BitMatrix
+where
+    Dist: Distribute,
+{
+    multiply_russians_blocked(dist, a, b, TABLES)
+}
+
+// The tables of as many groups as fit into `memory` words are built at once (in parallel). Then
+// each band of the result rows goes through all of them, one row at a time, so the row stays in
+// the cache.
+fn multiply_russians_blocked<Dist>(dist: &Dist, a: &BitMatrix, b: &BitMatrix, memory: usize)
+    -> BitMatrix
+where
+    Dist: Distribute,
+{
+    let mut result = result_for(a, b);
+    let stride = b.stride;
+    if stride == 0 || a.stride == 0 {
+        return result;
+    }
+
+    let groups = (b.height + GROUP - 1) / GROUP;
+    let table_len = (1 << GROUP) * stride;
+    let block = (memory / table_len).max(1).min(groups);
+    let mut tables = vec![0; block * table_len];
+    for start in (0..(groups + block - 1) / block).map(|i| i * block) {
+        let count = block.min(groups - start);
+        let mut tasks = tables
+            .chunks_mut(table_len)
+            .take(count)
+            .enumerate()
+            .collect::<Vec<_>>();
+        dist.run(b.width, &mut tasks, |&mut (i, ref mut table)| group_table(table, b, start + i));
+
+        let tables = &tables[..count * table_len];
+        let mut tasks = result.content
+            .chunks_mut(BAND * stride)
+            .zip(a.content.chunks(BAND * a.stride))
+            .collect::<Vec<_>>();
+        dist.run(a.height, &mut tasks, |&mut (ref mut r, a)| {
+            for (r_row, a_row) in r.chunks_mut(stride).zip(a.chunks(words(b.height))) {
+                for (i, table) in tables.chunks(table_len).enumerate() {
+                    // The groups never cross the words of a
+                    let first = (start + i) * GROUP;
+                    let word = a_row[first / BITS] >> (first % BITS);
+                    let mask = word as usize & ((1 << GROUP) - 1);
+                    if mask != 0 {
+                        or_into(r_row, &table[mask * stride .. (mask + 1) * stride]);
+                    }
+                }
+            }
+        });
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use typenum::U64;
+
+    use ::simple;
+    use ::simple::tests::SHAPES;
+    use ::znot::{DontDistribute, RayonDistribute};
+
+    fn random(w: usize, h: usize, density: Element) -> (Matrix, BitMatrix) {
+        let bits = BitMatrix::from_dense(&Matrix::random(w, h), 10. * (1. - density));
+        (bits.to_dense(), bits)
+    }
+
+    #[test]
+    fn conversions() {
+        let (dense, bits) = random(130, 7, 0.5);
+        assert_eq!(bits, BitMatrix::from_dense(&dense, 0.5));
+        let ones = dense.rows().flat_map(|row| row.iter()).filter(|&&val| val == 1.).count();
+        assert_eq!(ones, bits.count_ones());
+        assert_eq!(Matrix::identity(3), BitMatrix::identity(3).to_dense());
+        assert!(BitMatrix::identity(65).get(64, 64));
+        assert!(!BitMatrix::identity(65).get(63, 64));
+    }
+
+    #[test]
+    fn products() {
+        let extra = [
+            // Rows of a single word, full or not
+            (3, 64, 64),
+            (65, 64, 63),
+            // Just over one word
+            (3, 70, 2),
+            // The two-group blocks below end right at k, or one row after it
+            (5, 16, 70),
+            (5, 17, 70),
+            (0, 5, 5),
+            (5, 0, 5),
+        ];
+        for &(m, k, n) in SHAPES.iter().chain(&extra) {
+            for &density in &[0.01, 0.1, 0.5] {
+                let (a_dense, a) = random(k, m, density);
+                let (b_dense, b) = random(n, k, density);
+                let expected = BitMatrix::from_dense(&simple::multiply(&a_dense, &b_dense), 0.5);
+
+                let paral = RayonDistribute(U64::new());
+                assert_eq!(expected, multiply(&DontDistribute, &a, &b));
+                assert_eq!(expected, multiply(&paral, &a, &b));
+                assert_eq!(expected, multiply_russians(&DontDistribute, &a, &b));
+                assert_eq!(expected, multiply_russians(&paral, &a, &b));
+                // Tables for only one or two groups at a time
+                for &memory in &[0, 2 * 256 * b.stride] {
+                    assert_eq!(expected, multiply_russians_blocked(&paral, &a, &b, memory));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::marker::PhantomData;
+
+use typenum::Unsigned;
+
+use super::simd;
+use super::simple::{self, Matrix};
+use super::znot::{self, Distribute, FragMultiplyAdd, Matrix as ZMat};
+
+/// Number of floating point operations needed to multiply m×k by k×n matrices.
+pub fn flops(m: usize, k: usize, n: usize) -> u64 {
+    2 * m as u64 * k as u64 * n as u64
+}
+
+/// A way to multiply two matrices, together with a model of how long it takes.
+pub trait Backend {
+    /// Estimated cost of multiplying m×k by k×n matrices.
+    ///
+    /// The units don't matter, the costs are only compared with each other.
+    fn cost(&self, m: usize, k: usize, n: usize) -> f64;
+    fn multiply(&self, a: &Matrix, b: &Matrix) -> Matrix;
+}
+
+/// The [`simple::multiply`](../simple/fn.multiply.html).
+///
+/// The cost is the number of flops times the `flop_cost`.
+#[derive(Clone, Copy, Debug)]
+pub struct SimpleBackend {
+    pub flop_cost: f64,
+}
+
+impl Default for SimpleBackend {
+    fn default() -> Self {
+        // Roughly the seconds per flop, from the measurements
+        SimpleBackend { flop_cost: 2e-9 }
+    }
+}
+
+impl Backend for SimpleBackend {
+    fn cost(&self, m: usize, k: usize, n: usize) -> f64 {
+        self.flop_cost * flops(m, k, n) as f64
+    }
+    fn multiply(&self, a: &Matrix, b: &Matrix) -> Matrix {
+        simple::multiply(a, b)
+    }
+}
+
+/// The [`simd::multiply`](../simd/fn.multiply.html).
+#[derive(Clone, Copy, Debug)]
+pub struct SimdBackend {
+    pub flop_cost: f64,
+}
+
+impl Default for SimdBackend {
+    fn default() -> Self {
+        SimdBackend { flop_cost: 1.2e-10 }
+    }
+}
+
+impl Backend for SimdBackend {
+    fn cost(&self, m: usize, k: usize, n: usize) -> f64 {
+        self.flop_cost * flops(m, k, n) as f64
+    }
+    fn multiply(&self, a: &Matrix, b: &Matrix) -> Matrix {
+        simd::multiply(a, b)
+    }
+}
+
+/// The [`znot::multiply`](../znot/fn.multiply.html).
+///
+/// As that one works only on square matrices of power of 2 multiples of `Frag`, the inputs are
+/// padded by zeros. The cost model accounts for the padding and for the conversions (by the
+/// `element_cost` per each converted element).
+pub struct ZOrderBackend<Frag, Dist, Mult> {
+    pub dist: Dist,
+    pub flop_cost: f64,
+    pub element_cost: f64,
+    _params: PhantomData<(Frag, Mult)>,
+}
+
+impl<Frag, Dist, Mult> ZOrderBackend<Frag, Dist, Mult> {
+    pub fn new(dist: Dist, flop_cost: f64, element_cost: f64) -> Self {
+        ZOrderBackend {
+            dist,
+            flop_cost,
+            element_cost,
+            _params: PhantomData,
+        }
+    }
+}
+
+impl<Frag, Dist: Default, Mult> Default for ZOrderBackend<Frag, Dist, Mult> {
+    fn default() -> Self {
+        Self::new(Dist::default(), 2.5e-11, 1e-8)
+    }
+}
+
+impl<Frag, Dist, Mult> ZOrderBackend<Frag, Dist, Mult>
+where
+    Frag: Unsigned,
+{
+    fn padded(m: usize, k: usize, n: usize) -> usize {
+        let mut size = Frag::USIZE;
+        while size < m.max(k).max(n) {
+            size *= 2;
+        }
+        size
+    }
+}
+
+impl<Frag, Dist, Mult> Backend for ZOrderBackend<Frag, Dist, Mult>
+where
+    Frag: Unsigned + Default,
+    Dist: Distribute,
+    Mult: FragMultiplyAdd,
+{
+    fn cost(&self, m: usize, k: usize, n: usize) -> f64 {
+        let size = Self::padded(m, k, n);
+        self.flop_cost * flops(size, size, size) as f64
+            + self.element_cost * (3 * size * size) as f64
+    }
+    fn multiply(&self, a: &Matrix, b: &Matrix) -> Matrix {
+        let size = Self::padded(a.height(), a.width(), b.width());
+        let pad = |m: &Matrix| {
+            let mut padded = Matrix::sized(size, size);
+            padded.set_block(0, 0, m);
+            ZMat::<Frag>::from(&padded)
+        };
+        let result = znot::multiply::<_, _, Mult>(&self.dist, &pad(a), &pad(b));
+        Matrix::from(&result).block(0, 0, b.width(), a.height())
+    }
+}
+
+/// The order in which to multiply the chain.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Plan {
+    /// The matrix with the given index in the chain.
+    Matrix(usize),
+    Product(Box<Plan>, Box<Plan>),
+}
+
+impl Display for Plan {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            Plan::Matrix(idx) => write!(fmt, "{}", idx),
+            Plan::Product(ref a, ref b) => write!(fmt, "({} {})", a, b),
+        }
+    }
+}
+
+/// The chosen plan with the estimates of how expensive it is.
+#[derive(Clone, Debug)]
+pub struct Estimate {
+    pub plan: Plan,
+    /// The cost, as computed by the backend's model.
+    pub cost: f64,
+    pub flops: u64,
+}
+
+/// Finds the cheapest order of multiplication for matrices of the given shapes.
+///
+/// The shapes are (height, width) of the matrices in the chain.
+pub fn plan<B: Backend>(shapes: &[(usize, usize)], backend: &B) -> Estimate {
+    assert!(!shapes.is_empty(), "Empty chain of matrices");
+    for pair in shapes.windows(2) {
+        assert_eq!(pair[0].1, pair[1].0, "Shapes of the matrices don't match");
+    }
+
+    // Matrix i is dims[i]×dims[i + 1]
+    let count = shapes.len();
+    let dims = shapes
+        .iter()
+        .map(|&(h, _)| h)
+        .chain(Some(shapes[count - 1].1))
+        .collect::<Vec<_>>();
+
+    // best[i][j] is the (cost, flops, split) of multiplying the matrices i..=j.
+    let mut best = vec![vec![(0.0, 0, 0); count]; count];
+    for len in 1..count {
+        for i in 0..count - len {
+            let j = i + len;
+            let choice = (i..j)
+                .map(|split| {
+                    let (left_cost, left_flops, _) = best[i][split];
+                    let (right_cost, right_flops, _) = best[split + 1][j];
+                    let (m, k, n) = (dims[i], dims[split + 1], dims[j + 1]);
+                    let cost = left_cost + right_cost + backend.cost(m, k, n);
+                    (cost, left_flops + right_flops + flops(m, k, n), split)
+                })
+                .min_by(|a, b| a.0.partial_cmp(&b.0).expect("Cost is NaN"))
+                .unwrap();
+            best[i][j] = choice;
+        }
+    }
+
+    fn build(best: &[Vec<(f64, u64, usize)>], i: usize, j: usize) -> Plan {
+        if i == j {
+            Plan::Matrix(i)
+        } else {
+            let split = best[i][j].2;
+            Plan::Product(Box::new(build(best, i, split)), Box::new(build(best, split + 1, j)))
+        }
+    }
+
+    Estimate {
+        plan: build(&best, 0, count - 1),
+        cost: best[0][count - 1].0,
+        flops: best[0][count - 1].1,
+    }
+}
+
+/// Multiplies the whole chain, in the cheapest order.
+///
+/// Returns the result together with the plan that was used.
+pub fn multiply<B: Backend>(matrices: &[&Matrix], backend: &B) -> (Matrix, Estimate) {
+    fn eval<'a, B: Backend>(plan: &Plan, matrices: &[&'a Matrix], backend: &B) -> Cow<'a, Matrix> {
+        match *plan {
+            Plan::Matrix(idx) => Cow::Borrowed(matrices[idx]),
+            Plan::Product(ref a, ref b) => {
+                let a = eval(a, matrices, backend);
+                let b = eval(b, matrices, backend);
+                Cow::Owned(backend.multiply(&a, &b))
+            }
+        }
+    }
+
+    let shapes = matrices
+        .iter()
+        .map(|m| (m.height(), m.width()))
+        .collect::<Vec<_>>();
+    let estimate = plan(&shapes, backend);
+    let result = eval(&estimate.plan, matrices, backend).into_owned();
+    (result, estimate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use typenum::{U4, U32};
+
+    use ::znot::{RayonDistribute, SimdMultiplyAdd};
+
+    #[test]
+    fn classic() {
+        let estimate = plan(&[(10, 100), (100, 5), (5, 50)], &SimpleBackend::default());
+        assert_eq!("((0 1) 2)", estimate.plan.to_string());
+        assert_eq!(2 * (10 * 100 * 5 + 10 * 5 * 50), estimate.flops);
+
+        let estimate = plan(&[(50, 5), (5, 100), (100, 10)], &SimpleBackend::default());
+        assert_eq!("(0 (1 2))", estimate.plan.to_string());
+    }
+
+    #[test]
+    fn single() {
+        let m = Matrix::random(3, 2);
+        let (result, estimate) = multiply(&[&m], &SimdBackend::default());
+        assert_eq!(m, result);
+        assert_eq!(Plan::Matrix(0), estimate.plan);
+        assert_eq!(0, estimate.flops);
+    }
+
+    fn check<B: Backend>(backend: &B) {
+        let shapes = [(3, 17), (17, 2), (2, 30), (30, 5), (5, 5)];
+        let matrices = shapes
+            .iter()
+            .map(|&(h, w)| Matrix::random(w, h))
+            .collect::<Vec<_>>();
+        let mut expected = matrices[0].clone();
+        for m in &matrices[1..] {
+            expected = simple::multiply(&expected, m);
+        }
+        let refs = matrices.iter().collect::<Vec<_>>();
+        let (result, _) = multiply(&refs, backend);
+        result.assert_approx(&expected);
+    }
+
+    #[test]
+    fn backends() {
+        check(&SimpleBackend::default());
+        check(&SimdBackend::default());
+        check(&ZOrderBackend::<U4, RayonDistribute<U32>, SimdMultiplyAdd>::default());
+    }
+}
@@ -0,0 +1,281 @@
+use std::fmt::Debug;
+use std::ops::{Add, IndexMut, Mul, Sub};
+
+use rand::{self, Rng};
+use typenum::Unsigned;
+
+use super::Element;
+use super::semiring::{self, Arithmetic};
+use super::simd;
+use super::simple::Matrix as Simple;
+use super::znot::{self, Distribute, FragMultiplyAdd, Matrix as ZMat};
+
+/// The type of the real and imaginary parts, `f32` or `f64`.
+pub trait Real
+where
+    Self: Copy + Debug + Default + PartialEq,
+    Self: Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self>,
+{
+    /// The real matrix one plane is stored in.
+    type Plane: Clone + Debug + PartialEq + IndexMut<(usize, usize), Output = Self>;
+    fn sized(w: usize, h: usize) -> Self::Plane;
+    fn random(w: usize, h: usize) -> Self::Plane;
+    fn width(plane: &Self::Plane) -> usize;
+    fn height(plane: &Self::Plane) -> usize;
+    fn zip_with<F: Fn(Self, Self) -> Self>(a: &Self::Plane, b: &Self::Plane, f: F) -> Self::Plane;
+    /// The real multiplication the 4M method is built from.
+    fn multiply(a: &Self::Plane, b: &Self::Plane) -> Self::Plane;
+}
+
+/// Single precision, with the SIMD kernels.
+impl Real for Element {
+    type Plane = Simple;
+    fn sized(w: usize, h: usize) -> Simple { Simple::sized(w, h) }
+    fn random(w: usize, h: usize) -> Simple { Simple::random(w, h) }
+    fn width(plane: &Simple) -> usize { plane.width() }
+    fn height(plane: &Simple) -> usize { plane.height() }
+    fn zip_with<F: Fn(Element, Element) -> Element>(a: &Simple, b: &Simple, f: F) -> Simple {
+        a.zip_with(b, f)
+    }
+    fn multiply(a: &Simple, b: &Simple) -> Simple { simd::multiply(a, b) }
+}
+
+type Wide = semiring::Matrix<Arithmetic<f64>>;
+
+/// Double precision. There are no SIMD kernels for `f64`, so it multiplies by the scalar loop.
+impl Real for f64 {
+    type Plane = Wide;
+    fn sized(w: usize, h: usize) -> Wide { Wide::sized(w, h) }
+    fn random(w: usize, h: usize) -> Wide {
+        let mut result = Wide::sized(w, h);
+        let mut rng = rand::thread_rng();
+        for x in 0..w {
+            for y in 0..h {
+                result[(x, y)] = rng.gen_range(0., 10.);
+            }
+        }
+        result
+    }
+    fn width(plane: &Wide) -> usize { plane.width() }
+    fn height(plane: &Wide) -> usize { plane.height() }
+    fn zip_with<F: Fn(f64, f64) -> f64>(a: &Wide, b: &Wide, f: F) -> Wide {
+        assert_eq!(a.width(), b.width());
+        assert_eq!(a.height(), b.height());
+        let mut result = Wide::sized(a.width(), a.height());
+        for y in 0..a.height() {
+            for x in 0..a.width() {
+                result[(x, y)] = f(a[(x, y)], b[(x, y)]);
+            }
+        }
+        result
+    }
+    fn multiply(a: &Wide, b: &Wide) -> Wide { semiring::multiply(a, b) }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Complex<T = Element> {
+    pub re: T,
+    pub im: T,
+}
+
+/// A complex matrix.
+///
+/// It is stored in the planar form ‒ the real and imaginary parts are separate real matrices.
+/// That way the real kernels can be used on them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Matrix<T: Real = Element> {
+    re: T::Plane,
+    im: T::Plane,
+}
+
+impl<T: Real> Matrix<T> {
+    pub fn new(re: T::Plane, im: T::Plane) -> Self {
+        assert_eq!(T::width(&re), T::width(&im));
+        assert_eq!(T::height(&re), T::height(&im));
+        Self { re, im }
+    }
+    pub fn sized(w: usize, h: usize) -> Self {
+        Self::new(T::sized(w, h), T::sized(w, h))
+    }
+    pub fn random(w: usize, h: usize) -> Self {
+        Self::new(T::random(w, h), T::random(w, h))
+    }
+    /// Converts from the interleaved (row-major array of complex numbers) representation.
+    pub fn from_interleaved(w: usize, h: usize, content: &[Complex<T>]) -> Self {
+        assert_eq!(content.len(), w * h);
+        let mut result = Self::sized(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let val = content[x + y * w];
+                result.re[(x, y)] = val.re;
+                result.im[(x, y)] = val.im;
+            }
+        }
+        result
+    }
+    pub fn to_interleaved(&self) -> Vec<Complex<T>> {
+        let mut result = Vec::with_capacity(self.width() * self.height());
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                result.push(self.get(x, y));
+            }
+        }
+        result
+    }
+    pub fn get(&self, x: usize, y: usize) -> Complex<T> {
+        Complex {
+            re: self.re[(x, y)],
+            im: self.im[(x, y)],
+        }
+    }
+    pub fn re(&self) -> &T::Plane { &self.re }
+    pub fn im(&self) -> &T::Plane { &self.im }
+    pub fn width(&self) -> usize { T::width(&self.re) }
+    pub fn height(&self) -> usize { T::height(&self.re) }
+}
+
+/// The straightforward multiplication, using four real multiplications.
+pub fn multiply_4m<T: Real>(a: &Matrix<T>, b: &Matrix<T>) -> Matrix<T> {
+    let rr = T::multiply(&a.re, &b.re);
+    let ii = T::multiply(&a.im, &b.im);
+    let ri = T::multiply(&a.re, &b.im);
+    let ir = T::multiply(&a.im, &b.re);
+    Matrix {
+        re: T::zip_with(&rr, &ii, |a, b| a - b),
+        im: T::zip_with(&ri, &ir, |a, b| a + b),
+    }
+}
+
+/// The 3M (Gauss) method, trading one real multiplication for few additions.
+///
+/// The real multiplications are done by `mult`.
+pub fn multiply_3m_by<T, M>(a: &Matrix<T>, b: &Matrix<T>, mult: M) -> Matrix<T>
+where
+    T: Real,
+    M: Fn(&T::Plane, &T::Plane) -> T::Plane,
+{
+    // re = ArBr - AiBi
+    // im = (Ar + Ai)(Br + Bi) - ArBr - AiBi
+    let rr = mult(&a.re, &b.re);
+    let ii = mult(&a.im, &b.im);
+    let a_sum = T::zip_with(&a.re, &a.im, |a, b| a + b);
+    let b_sum = T::zip_with(&b.re, &b.im, |a, b| a + b);
+    let sums = mult(&a_sum, &b_sum);
+    Matrix {
+        re: T::zip_with(&rr, &ii, |a, b| a - b),
+        im: T::zip_with(&T::zip_with(&sums, &rr, |a, b| a - b), &ii, |a, b| a - b),
+    }
+}
+
+/// The 3M method with the real multiplications done in the Z-order.
+///
+/// Therefore the matrices must be square, with the size being a power of 2 multiple of `Frag`.
+pub fn multiply_3m<Frag, Dist, Mult>(dist: &Dist, a: &Matrix, b: &Matrix) -> Matrix
+where
+    Frag: Unsigned + Default,
+    Dist: Distribute,
+    Mult: FragMultiplyAdd,
+{
+    multiply_3m_by(a, b, |a: &Simple, b: &Simple| {
+        let a_z = ZMat::<Frag>::from(a);
+        let b_z = ZMat::<Frag>::from(b);
+        Simple::from(&znot::multiply::<_, _, Mult>(dist, &a_z, &b_z))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use typenum::{U1, U4, U32};
+
+    use ::znot::{RayonDistribute, SimdMultiplyAdd};
+
+    fn reference<T: Real>(a: &Matrix<T>, b: &Matrix<T>) -> Matrix<T> {
+        let mut result = Matrix::sized(b.width(), a.height());
+        for y in 0..a.height() {
+            for x in 0..b.width() {
+                let mut sum = Complex::default();
+                for p in 0..a.width() {
+                    let av = a.get(p, y);
+                    let bv = b.get(x, p);
+                    sum.re = sum.re + av.re * bv.re - av.im * bv.im;
+                    sum.im = sum.im + av.re * bv.im + av.im * bv.re;
+                }
+                result.re[(x, y)] = sum.re;
+                result.im[(x, y)] = sum.im;
+            }
+        }
+        result
+    }
+
+    fn approx_eq(a: &Matrix, b: &Matrix) {
+        a.re.assert_approx(&b.re);
+        a.im.assert_approx(&b.im);
+    }
+
+    fn approx_eq_64(a: &Matrix<f64>, b: &Matrix<f64>) {
+        for (a, b) in a.to_interleaved().into_iter().zip(b.to_interleaved()) {
+            // Far tighter than anything f32 could do with these magnitudes
+            assert!((a.re - b.re).abs() < 1e-9, "{:?} != {:?}", a, b);
+            assert!((a.im - b.im).abs() < 1e-9, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn exact() {
+        // (1 + 2i) * (3 - i) = 5 + 5i
+        let a = Matrix::from_interleaved(1, 1, &[Complex { re: 1., im: 2. }]);
+        let b = Matrix::from_interleaved(1, 1, &[Complex { re: 3., im: -1. }]);
+        let expected = vec![Complex { re: 5., im: 5. }];
+        assert_eq!(expected, multiply_4m(&a, &b).to_interleaved());
+        let r = multiply_3m::<U1, _, SimdMultiplyAdd>(&RayonDistribute(U32::new()), &a, &b);
+        assert_eq!(expected, r.to_interleaved());
+    }
+
+    #[test]
+    fn rect_4m() {
+        let a = Matrix::random(5, 3);
+        let b = Matrix::random(2, 5);
+        approx_eq(&reference(&a, &b), &multiply_4m(&a, &b));
+    }
+
+    #[test]
+    fn square_3m() {
+        for shift in 0..5 {
+            let size = 4 << shift;
+            let a = Matrix::random(size, size);
+            let b = Matrix::random(size, size);
+            let expected = reference(&a, &b);
+            approx_eq(&expected, &multiply_4m(&a, &b));
+            let r = multiply_3m::<U4, _, SimdMultiplyAdd>(&RayonDistribute(U32::new()), &a, &b);
+            approx_eq(&expected, &r);
+        }
+    }
+
+    #[test]
+    fn exact_64() {
+        let a = Matrix::<f64>::from_interleaved(1, 1, &[Complex { re: 1., im: 2. }]);
+        let b = Matrix::<f64>::from_interleaved(1, 1, &[Complex { re: 3., im: -1. }]);
+        let expected = vec![Complex { re: 5., im: 5. }];
+        assert_eq!(expected, multiply_4m(&a, &b).to_interleaved());
+        assert_eq!(expected, multiply_3m_by(&a, &b, f64::multiply).to_interleaved());
+    }
+
+    #[test]
+    fn both_64() {
+        let a = Matrix::<f64>::random(7, 3);
+        let b = Matrix::<f64>::random(5, 7);
+        let expected = reference(&a, &b);
+        approx_eq_64(&expected, &multiply_4m(&a, &b));
+        approx_eq_64(&expected, &multiply_3m_by(&a, &b, f64::multiply));
+    }
+
+    #[test]
+    fn interleaved() {
+        let a = Matrix::<Element>::random(3, 2);
+        assert_eq!(a, Matrix::from_interleaved(3, 2, &a.to_interleaved()));
+        let a = Matrix::<f64>::random(3, 2);
+        assert_eq!(a, Matrix::from_interleaved(3, 2, &a.to_interleaved()));
+    }
+}
@@ -1,9 +1,12 @@
 #![feature(nll)]
 extern crate bincode;
+extern crate crossbeam_utils;
 extern crate failure;
 #[macro_use] // tuplify macro ‒ abused somewhere else, but who cares
 extern crate faster;
 extern crate itertools;
+#[cfg(target_os = "linux")]
+extern crate libc;
 extern crate rand;
 extern crate rayon;
 extern crate serde;
@@ -12,8 +15,20 @@ extern crate serde_derive;
 extern crate smallvec;
 extern crate typenum;
 
+pub mod batch;
+pub mod bitmat;
+pub mod chain;
+pub mod complex;
+pub mod lu;
+pub mod numa;
+pub mod quant;
+pub mod recursive;
+pub mod semiring;
 pub mod simd;
 pub mod simple;
+pub mod sparse;
+pub mod strassen;
+pub mod tiled;
 pub mod znot;
 
 pub type Element = f32;
@@ -0,0 +1,311 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use failure::Fail;
+use typenum::U256;
+
+use super::Element;
+use super::recursive::{self, View, ViewMut};
+use super::simple::Matrix;
+use super::znot::{Distribute, RayonDistribute, SimdMultiplyAdd};
+
+/// The matrix is singular (or too close to being singular to work with).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Singular;
+
+impl Display for Singular {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "Singular matrix")
+    }
+}
+
+impl Fail for Singular {}
+
+// Number of columns factorized in one panel.
+const BLOCK: usize = 64;
+// The leaf size of the multiplication doing the trailing updates.
+const LEAF: usize = 64;
+
+/// LU decomposition with partial pivoting.
+#[derive(Clone, Debug)]
+pub struct Lu {
+    // Both L (below the diagonal, with implicit ones on the diagonal) and U (the rest).
+    lu: Matrix,
+    // Row i of the decomposition corresponds to row permutation[i] of the original matrix.
+    permutation: Vec<usize>,
+    // If the number of row swaps is odd.
+    odd: bool,
+}
+
+impl Lu {
+    /// Decomposes the matrix, with the trailing updates distributed by rayon.
+    pub fn decompose(matrix: &Matrix) -> Result<Self, Singular> {
+        Self::decompose_blocked(&RayonDistribute(U256::new()), matrix, BLOCK)
+    }
+    /// Decomposes the matrix, factorizing `block` columns at a time.
+    ///
+    /// After each panel of columns, the rest of the matrix is updated by a single large
+    /// multiplication, distributed by `dist`.
+    ///
+    /// Pivots too small relative to the largest element of the matrix are considered rounding
+    /// errors and the matrix singular, so it is safe to [`solve`](#method.solve) with the result.
+    pub fn decompose_blocked<Dist: Distribute>(dist: &Dist, matrix: &Matrix, block: usize)
+        -> Result<Self, Singular>
+    {
+        let scale = matrix.rows()
+            .flat_map(|row| row.iter())
+            .fold(0.0, |max: Element, val| max.max(val.abs()));
+        let tolerance = scale * matrix.width() as Element * ::std::f32::EPSILON;
+        Self::decompose_tolerant(dist, matrix, block, tolerance)
+    }
+    /// Decomposes the matrix, considering it singular if a pivot is not above `tolerance`.
+    pub fn decompose_tolerant<Dist: Distribute>(
+        dist: &Dist,
+        matrix: &Matrix,
+        block: usize,
+        tolerance: Element,
+    ) -> Result<Self, Singular> {
+        assert_eq!(matrix.width(), matrix.height(), "Only square matrices can be decomposed");
+        assert!(block > 0, "Block must not be empty");
+
+        let n = matrix.width();
+        let mut a = matrix.clone();
+        let mut permutation = (0..n).collect::<Vec<_>>();
+        let mut odd = false;
+
+        let mut k0 = 0;
+        while k0 < n {
+            let k1 = (k0 + block).min(n);
+
+            // Factorize the panel, columns k0..k1
+            for j in k0..k1 {
+                let mut pivot = j;
+                for i in j + 1..n {
+                    if a[(j, i)].abs() > a[(j, pivot)].abs() {
+                        pivot = i;
+                    }
+                }
+                // Written this way to catch NaNs too
+                if !(a[(j, pivot)].abs() > tolerance) || a[(j, pivot)].is_infinite() {
+                    return Err(Singular);
+                }
+                if pivot != j {
+                    a.swap_rows(j, pivot);
+                    permutation.swap(j, pivot);
+                    odd = !odd;
+                }
+
+                let diag = a[(j, j)];
+                for i in j + 1..n {
+                    let l = a[(j, i)] / diag;
+                    a[(j, i)] = l;
+                    for c in j + 1..k1 {
+                        let update = l * a[(c, j)];
+                        a[(c, i)] -= update;
+                    }
+                }
+            }
+
+            // The rows of U right of the panel (U12 = L11⁻¹ * A12)
+            for j in k0..k1 {
+                for i in j + 1..k1 {
+                    let l = a[(j, i)];
+                    for c in k1..n {
+                        let update = l * a[(c, j)];
+                        a[(c, i)] -= update;
+                    }
+                }
+            }
+
+            // The trailing update (A22 -= L21 * U12)
+            if k1 < n {
+                // L21 shares the rows with A22, so it is the only part copied out (negated, to
+                // turn the multiply-add into the subtraction). It is only as wide as the panel.
+                let w = k1 - k0;
+                let mut l21 = Vec::with_capacity((n - k1) * w);
+                for row in a.rows().skip(k1) {
+                    l21.extend(row[k0 .. k1].iter().map(|val| -val));
+                }
+                let slice = a.slice_mut();
+                let (top, bottom) = slice.content.split_at_mut(k1 * n);
+                let l21 = View {
+                    content: &l21,
+                    stride: w,
+                    width: w,
+                    height: n - k1,
+                };
+                let u12 = View {
+                    content: &top[k0 * n + k1 ..],
+                    stride: n,
+                    width: n - k1,
+                    height: w,
+                };
+                let mut a22 = ViewMut {
+                    content: &mut bottom[k1 ..],
+                    stride: n,
+                    width: n - k1,
+                    height: n - k1,
+                };
+                recursive::multiply_add::<_, SimdMultiplyAdd>(dist, &mut a22, l21, u12, LEAF);
+            }
+
+            k0 = k1;
+        }
+
+        Ok(Lu {
+            lu: a,
+            permutation,
+            odd,
+        })
+    }
+    pub fn size(&self) -> usize { self.lu.width() }
+    /// Solves `A * x = b`.
+    pub fn solve(&self, b: &[Element]) -> Vec<Element> {
+        let n = self.size();
+        assert_eq!(b.len(), n);
+
+        let mut x = self.permutation.iter().map(|&p| b[p]).collect::<Vec<_>>();
+        // L * y = P * b
+        for i in 0..n {
+            let sum = (0..i).map(|j| self.lu[(j, i)] * x[j]).sum::<Element>();
+            x[i] -= sum;
+        }
+        // U * x = y
+        for i in (0..n).rev() {
+            let sum = (i + 1..n).map(|j| self.lu[(j, i)] * x[j]).sum::<Element>();
+            x[i] = (x[i] - sum) / self.lu[(i, i)];
+        }
+        x
+    }
+    pub fn det(&self) -> Element {
+        let diag = (0..self.size()).map(|i| self.lu[(i, i)]).product::<Element>();
+        if self.odd {
+            -diag
+        } else {
+            diag
+        }
+    }
+    pub fn inverse(&self) -> Matrix {
+        let n = self.size();
+        let mut result = Matrix::sized(n, n);
+        let mut unit = vec![0.; n];
+        for c in 0..n {
+            unit[c] = 1.;
+            for (r, val) in self.solve(&unit).into_iter().enumerate() {
+                result[(c, r)] = val;
+            }
+            unit[c] = 0.;
+        }
+        result
+    }
+}
+
+pub fn solve(a: &Matrix, b: &[Element]) -> Result<Vec<Element>, Singular> {
+    Ok(Lu::decompose(a)?.solve(b))
+}
+
+/// The determinant, as the product of the pivots.
+///
+/// It is zero whenever [`Lu::decompose`](struct.Lu.html#method.decompose) considers the matrix
+/// singular, so it agrees with `solve` and `inverse`. A matrix scaled uniformly small still gets
+/// its (small) determinant, the tolerance is relative to the largest element.
+pub fn det(a: &Matrix) -> Element {
+    Lu::decompose(a).map(|lu| lu.det()).unwrap_or(0.)
+}
+
+pub fn inverse(a: &Matrix) -> Result<Matrix, Singular> {
+    Ok(Lu::decompose(a)?.inverse())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use typenum::U32;
+
+    use ::simple;
+    use ::znot::DontDistribute;
+
+    fn matrix(rows: &[&[Element]]) -> Matrix {
+        let mut result = Matrix::sized(rows[0].len(), rows.len());
+        for (y, row) in rows.iter().enumerate() {
+            for (x, &val) in row.iter().enumerate() {
+                result[(x, y)] = val;
+            }
+        }
+        result
+    }
+
+    fn approx(a: &[Element], b: &[Element]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn small_det() {
+        let diag = matrix(&[&[2., 0., 0.], &[0., 3., 0.], &[0., 0., 4.]]);
+        assert_eq!(24., det(&diag));
+        // Needs a row swap
+        let swap = matrix(&[&[0., 1.], &[1., 0.]]);
+        assert_eq!(-1., det(&swap));
+        let m = matrix(&[&[1., 2.], &[3., 4.]]);
+        assert!((det(&m) + 2.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn small_solve() {
+        let a = matrix(&[&[2., 1., 1.], &[1., 3., 2.], &[1., 0., 0.]]);
+        approx(&[1., 2., 3.], &solve(&a, &[7., 13., 1.]).unwrap());
+        for &block in &[1, 2, 3] {
+            let lu = Lu::decompose_blocked(&DontDistribute, &a, block).unwrap();
+            approx(&[1., 2., 3.], &lu.solve(&[7., 13., 1.]));
+        }
+    }
+
+    #[test]
+    fn small_inverse() {
+        let a = matrix(&[&[4., 7.], &[2., 6.]]);
+        let expected = matrix(&[&[0.6, -0.7], &[-0.2, 0.4]]);
+        inverse(&a).unwrap().assert_approx(&expected);
+    }
+
+    #[test]
+    fn singular() {
+        let a = matrix(&[&[1., 2.], &[2., 4.]]);
+        assert_eq!(Singular, Lu::decompose(&a).unwrap_err());
+        assert_eq!(0., det(&a));
+        assert_eq!(Singular, inverse(&Matrix::sized(3, 3)).unwrap_err());
+        assert_eq!(0., det(&matrix(&[&[1., 0.], &[0., ::std::f32::NAN]])));
+    }
+
+    #[test]
+    fn badly_scaled() {
+        // Too small a pivot to solve with, relative to the other one, and det agrees
+        let diag = matrix(&[&[1e4, 0.], &[0., 1e-4]]);
+        assert_eq!(Singular, Lu::decompose(&diag).unwrap_err());
+        assert_eq!(0., det(&diag));
+
+        let tiny = matrix(&[&[1e-15, 2e-15], &[3e-15, 4e-15]]);
+        let expected = -2e-30;
+        assert!((det(&tiny) - expected).abs() < 1e-6 * 2e-30, "{}", det(&tiny));
+        // All the pivots are small in the same way, so this one is fine
+        approx(&[1., 1.], &Lu::decompose(&tiny).unwrap().solve(&[3e-15, 7e-15]));
+    }
+
+    #[test]
+    fn large() {
+        let n = 150;
+        let mut a = Matrix::random(n, n);
+        for i in 0..n {
+            // Keep it well conditioned
+            a[(i, i)] += 10. * n as Element;
+        }
+        for &block in &[1, 7, 64, 200] {
+            let lu = Lu::decompose_blocked(&DontDistribute, &a, block).unwrap();
+            simple::multiply(&a, &lu.inverse()).assert_approx(&Matrix::identity(n));
+            let lu = Lu::decompose_blocked(&RayonDistribute(U32::new()), &a, block).unwrap();
+            simple::multiply(&a, &lu.inverse()).assert_approx(&Matrix::identity(n));
+        }
+    }
+}
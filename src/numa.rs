@@ -0,0 +1,526 @@
+use std::cell::Cell;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crossbeam_utils::scoped;
+use failure::{self, Error};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use rayon::prelude::*;
+use typenum::Unsigned;
+
+use super::simple::Matrix as Simple;
+use super::znot::{self, Distribute, DontDistribute, Matrix as ZMat, Threshold};
+
+const SYSFS: &str = "/sys/devices/system/node";
+const CPU_SYSFS: &str = "/sys/devices/system/cpu";
+
+thread_local! {
+    // The index of the node the current thread belongs to, if it is one of our workers.
+    static NODE: Cell<Option<usize>> = Cell::new(None);
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Node {
+    pub id: usize,
+    /// The CPUs of the node. Empty means any CPU (the threads are not pinned).
+    pub cpus: Vec<usize>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Topology {
+    nodes: Vec<Node>,
+}
+
+/// Parses the kernel's format of CPU lists, like `0-3,8,10-11`.
+pub fn parse_cpulist(list: &str) -> Result<Vec<usize>, Error> {
+    let mut result = Vec::new();
+    for part in list.trim().split(',').filter(|part| !part.is_empty()) {
+        let mut bounds = part.splitn(2, '-');
+        let first = bounds.next().unwrap().parse::<usize>()?;
+        let last = match bounds.next() {
+            Some(last) => last.parse::<usize>()?,
+            None => first,
+        };
+        if first > last {
+            return Err(failure::err_msg(format!("Invalid CPU range {}", part)));
+        }
+        result.extend(first..last + 1);
+    }
+    Ok(result)
+}
+
+impl Topology {
+    /// Finds the NUMA nodes of this machine.
+    ///
+    /// If they are not available (it is not Linux, there's no sysfs, ...), the whole machine is
+    /// considered a single node.
+    pub fn detect() -> Self {
+        match Self::from_dir(Path::new(SYSFS)) {
+            Ok(ref topology) if !topology.nodes.is_empty() => topology.clone(),
+            _ => Self::single(),
+        }
+    }
+    /// Reads the topology from a directory laid out as `/sys/devices/system/node`.
+    pub fn from_dir(dir: &Path) -> Result<Self, Error> {
+        let mut nodes = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if !name.starts_with("node") {
+                continue;
+            }
+            let id = match name["node".len()..].parse() {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+            let cpus = parse_cpulist(&fs::read_to_string(entry.path().join("cpulist"))?)?;
+            // Nodes with only memory have nothing to run the tasks on
+            if !cpus.is_empty() {
+                nodes.push(Node { id, cpus });
+            }
+        }
+        nodes.sort_by_key(|node| node.id);
+        Ok(Topology { nodes })
+    }
+    /// The whole machine as a single node.
+    pub fn single() -> Self {
+        Topology {
+            nodes: vec![Node { id: 0, cpus: Vec::new() }],
+        }
+    }
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn pin(cpus: &[usize]) -> Result<(), Error> {
+    use std::io::Error as IoError;
+    use std::mem;
+
+    use libc;
+
+    if cpus.is_empty() {
+        return Ok(());
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(IoError::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn pin(_: &[usize]) -> Result<(), Error> {
+    Ok(())
+}
+
+// The CPUs the process is allowed to run on (restricted eg. by taskset or the cgroups of a
+// container).
+#[cfg(target_os = "linux")]
+fn affinity() -> Result<Vec<usize>, Error> {
+    use std::io::Error as IoError;
+    use std::mem;
+
+    use libc;
+
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        if libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(IoError::last_os_error().into());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize).filter(|&cpu| libc::CPU_ISSET(cpu, &set)).collect())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn affinity() -> Result<Vec<usize>, Error> {
+    Err(failure::err_msg("Pinning to CPUs is supported only on Linux"))
+}
+
+// Pins a short-lived thread to each of the CPU sets in turn, so the workers can do the same later
+// on without checking.
+pub(crate) fn check_pinning(sets: &[Vec<usize>]) -> Result<(), Error> {
+    scoped::scope(|scope| {
+        let check = scope.spawn(|| sets.iter().map(|cpus| pin(cpus)).collect::<Result<(), _>>());
+        check
+            .join()
+            .unwrap_or_else(|_| Err(failure::err_msg("Checking the pinning panicked")))
+    })
+}
+
+// A pool builder with a worker for each of the CPU sets, pinned to it, and running the `start`
+// first. With no sets, rayon chooses the number of workers and they are not pinned.
+fn pinned_builder<S>(workers: Vec<Vec<usize>>, start: S) -> Result<ThreadPoolBuilder, Error>
+where
+    S: Fn(usize) + Send + Sync + 'static,
+{
+    check_pinning(&workers)?;
+    let builder = ThreadPoolBuilder::new()
+        // 0 is the rayon's default
+        .num_threads(workers.len())
+        .start_handler(move |i| {
+            if let Some(cpus) = workers.get(i) {
+                // Checked above, so this doesn't fail
+                let _ = pin(cpus);
+            }
+            start(i);
+        });
+    Ok(builder)
+}
+
+/// Where to run the worker threads.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Pinning {
+    /// Wherever the OS schedules them.
+    Off,
+    /// Each pinned to its own physical core, leaving the SMT siblings (hyper-threads) idle.
+    Cores,
+    /// Each pinned to its own logical CPU, SMT siblings included.
+    Threads,
+}
+
+impl Default for Pinning {
+    fn default() -> Self {
+        Pinning::Off
+    }
+}
+
+impl FromStr for Pinning {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "off" => Ok(Pinning::Off),
+            "cores" => Ok(Pinning::Cores),
+            "threads" => Ok(Pinning::Threads),
+            _ => Err(failure::err_msg(format!("Unknown pinning {}, use off, cores or threads", s))),
+        }
+    }
+}
+
+impl Pinning {
+    /// The CPUs for the workers, one worker per CPU, out of the `allowed` ones.
+    ///
+    /// Empty `allowed` means all the online CPUs. The first SMT siblings of all the cores come
+    /// before the second ones, so a pool with fewer workers still spreads over the cores. Only the
+    /// CPUs of the process' affinity mask are used.
+    ///
+    /// If the CPU topology can't be read or none of the CPUs can be used, this fails instead of
+    /// silently running unpinned.
+    pub fn cpus(&self, allowed: &[usize]) -> Result<Vec<usize>, Error> {
+        if *self == Pinning::Off {
+            return Ok(Vec::new());
+        }
+        let usable = affinity()?;
+        let allowed = if allowed.is_empty() {
+            usable
+        } else {
+            allowed.iter().cloned().filter(|cpu| usable.contains(cpu)).collect()
+        };
+        // Empty would mean all of them to cpus_from_dir
+        if allowed.is_empty() {
+            return Err(failure::err_msg("None of the CPUs to pin to is allowed"));
+        }
+        self.cpus_from_dir(Path::new(CPU_SYSFS), &allowed)
+    }
+    /// Like [`cpus`](#method.cpus), with the topology from a directory laid out as
+    /// `/sys/devices/system/cpu`.
+    pub fn cpus_from_dir(&self, dir: &Path, allowed: &[usize]) -> Result<Vec<usize>, Error> {
+        if *self == Pinning::Off {
+            return Ok(Vec::new());
+        }
+        let is_allowed = |cpu: &usize| allowed.is_empty() || allowed.contains(cpu);
+        let online = parse_cpulist(&fs::read_to_string(dir.join("online"))?)?;
+        // (which sibling on its core, cpu)
+        let mut cpus = Vec::new();
+        for cpu in online.into_iter().filter(&is_allowed) {
+            let siblings = dir
+                .join(format!("cpu{}", cpu))
+                .join("topology")
+                .join("thread_siblings_list");
+            let rank = parse_cpulist(&fs::read_to_string(siblings)?)?
+                .into_iter()
+                .filter(&is_allowed)
+                .position(|sibling| sibling == cpu)
+                .unwrap_or(0);
+            if rank == 0 || *self == Pinning::Threads {
+                cpus.push((rank, cpu));
+            }
+        }
+        cpus.sort();
+        Ok(cpus.into_iter().map(|(_, cpu)| cpu).collect())
+    }
+    fn builder(&self, allowed: &[usize]) -> Result<ThreadPoolBuilder, Error> {
+        let workers = self.cpus(allowed)?
+            .into_iter()
+            .map(|cpu| vec![cpu])
+            .collect();
+        pinned_builder(workers, |_| ())
+    }
+    /// A thread pool with a worker for each of the chosen [`cpus`](#method.cpus).
+    pub fn pool(&self, allowed: &[usize]) -> Result<ThreadPool, Error> {
+        self.builder(allowed)?.build().map_err(Error::from)
+    }
+    /// Sets up the global rayon thread pool (the one used by
+    /// [`RayonDistribute`](../znot/struct.RayonDistribute.html)) this way.
+    ///
+    /// This works only before the global pool is used for the first time.
+    pub fn install_global(&self) -> Result<(), Error> {
+        self.builder(&[])?.build_global().map_err(Error::from)
+    }
+}
+
+/// Distributes the tasks among the NUMA nodes, each having its own thread pool pinned to its CPUs.
+///
+/// The top level tasks are assigned to the nodes round-robin and anything below them stays inside
+/// the node's pool. With `znot`, each quadrant of the result is therefore computed by a single
+/// node and, as the result is first written by that node, the memory of the quadrant gets
+/// allocated there too. The inputs can be placed the same way by [`convert`](#method.convert).
+///
+/// With a single node, this is just a [`PoolDistribute`](../znot/struct.PoolDistribute.html).
+///
+/// The workers are pinned to the CPUs of their node. With [`Pinning`](enum.Pinning.html) other
+/// than `Off`, each worker is further pinned to a single CPU of the node.
+pub struct NumaDistribute<Limit: Threshold> {
+    pools: Vec<ThreadPool>,
+    limit: Limit,
+}
+
+impl<Limit: Threshold> NumaDistribute<Limit> {
+    pub fn new(topology: &Topology, limit: Limit) -> Result<Self, Error> {
+        Self::with_pinning(topology, limit, Pinning::Off)
+    }
+    pub fn with_pinning(topology: &Topology, limit: Limit, pinning: Pinning)
+        -> Result<Self, Error>
+    {
+        let pools = topology.nodes
+            .iter()
+            .enumerate()
+            .map(|(idx, node)| {
+                let id = node.id;
+                let cpus = pinning.cpus(&node.cpus)?;
+                let workers = if !cpus.is_empty() {
+                    cpus.into_iter().map(|cpu| vec![cpu]).collect()
+                } else if node.cpus.is_empty() {
+                    Vec::new()
+                } else {
+                    // Only the CPUs of the node we may run on. If there are none, the workers
+                    // are left unpinned.
+                    let usable = affinity()?;
+                    let usable = node.cpus
+                        .iter()
+                        .cloned()
+                        .filter(|cpu| usable.contains(cpu))
+                        .collect::<Vec<_>>();
+                    node.cpus.iter().map(|_| usable.clone()).collect()
+                };
+                pinned_builder(workers, move |_| NODE.with(|node| node.set(Some(idx))))?
+                    .thread_name(move |i| format!("numa-{}-{}", id, i))
+                    .build()
+                    .map_err(Error::from)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { pools, limit })
+    }
+    pub fn nodes(&self) -> usize {
+        self.pools.len()
+    }
+    /// Converts the matrix into the Z-order, each quadrant on the node that processes it.
+    pub fn convert<Frag: Unsigned + Default>(&self, matrix: &Simple) -> ZMat<Frag> {
+        let size = matrix.width();
+        let frag = Frag::USIZE;
+        assert_eq!(size, matrix.height(), "We support only square matrices");
+        if self.pools.len() == 1 || size == frag {
+            return ZMat::from(matrix);
+        }
+
+        // The zeroed memory is not touched yet (it comes fresh from the OS), so the first write
+        // decides where it lives.
+        let mut content = vec![0.; size * size];
+        let s = size / 2;
+        let nodes = self.pools.len();
+        scoped::scope(|scope| {
+            for (i, quad) in content.chunks_mut(s * s).enumerate() {
+                let pool = &self.pools[i % nodes];
+                let (x0, y0) = ((i % 2) * s, (i / 2) * s);
+                scope.spawn(move || {
+                    pool.install(|| {
+                        znot::z_order_into(quad, s, frag, |x, y| matrix[(x0 + x, y0 + y)]);
+                    });
+                });
+            }
+        });
+
+        ZMat::from_content(size, content)
+    }
+}
+
+impl<Limit: Threshold> Distribute for NumaDistribute<Limit> {
+    fn run<I: Send, F: Fn(&mut I) + Send + Sync>(&self, size: usize, tasks: &mut [I], f: F) {
+        if size < self.limit.threshold() {
+            DontDistribute.run(size, tasks, f);
+        } else if NODE.with(|node| node.get()).is_some() {
+            // Already inside one of the nodes, stay there.
+            tasks
+                .into_par_iter()
+                .for_each(f);
+        } else if self.pools.len() == 1 {
+            self.pools[0].install(|| {
+                tasks
+                    .into_par_iter()
+                    .for_each(f);
+            });
+        } else {
+            let nodes = self.pools.len();
+            let mut groups = (0..nodes).map(|_| Vec::new()).collect::<Vec<_>>();
+            for (i, task) in tasks.iter_mut().enumerate() {
+                groups[i % nodes].push(task);
+            }
+            let f = &f;
+            scoped::scope(|scope| {
+                for (pool, group) in self.pools.iter().zip(groups) {
+                    scope.spawn(move || {
+                        pool.install(|| {
+                            group
+                                .into_par_iter()
+                                .for_each(|task| f(task));
+                        });
+                    });
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    use typenum::U4;
+
+    use ::simple;
+    use ::znot::{Cutoff, SimdMultiplyAdd};
+
+    #[test]
+    fn cpulist() {
+        assert_eq!(vec![0, 1, 2, 3, 8, 10, 11], parse_cpulist("0-3,8,10-11\n").unwrap());
+        assert_eq!(vec![5], parse_cpulist("5").unwrap());
+        assert!(parse_cpulist("").unwrap().is_empty());
+        assert!(parse_cpulist("3-1").is_err());
+        assert!(parse_cpulist("x").is_err());
+    }
+
+    #[test]
+    fn sysfs() {
+        let dir = env::temp_dir().join(format!("fastmatmult-numa-{}", process::id()));
+        for &(node, cpus) in &[("node1", "2-3\n"), ("node0", "0-1\n"), ("node2", "\n")] {
+            fs::create_dir_all(dir.join(node)).unwrap();
+            fs::write(dir.join(node).join("cpulist"), cpus).unwrap();
+        }
+        fs::write(dir.join("possible"), "0-2\n").unwrap();
+
+        let topology = Topology::from_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let expected = vec![
+            Node { id: 0, cpus: vec![0, 1] },
+            Node { id: 1, cpus: vec![2, 3] },
+        ];
+        assert_eq!(expected, topology.unwrap().nodes);
+    }
+
+    #[test]
+    fn pinning_parse() {
+        assert_eq!(Pinning::Off, "off".parse().unwrap());
+        assert_eq!(Pinning::Cores, "cores".parse().unwrap());
+        assert_eq!(Pinning::Threads, "threads".parse().unwrap());
+        assert!("all".parse::<Pinning>().is_err());
+    }
+
+    #[test]
+    fn pinning_sysfs() {
+        let dir = env::temp_dir().join(format!("fastmatmult-pin-{}", process::id()));
+        // Two cores with two threads each, the last CPU is offline
+        let siblings = [("cpu0", "0,2\n"), ("cpu1", "1,3\n"), ("cpu2", "0,2\n"), ("cpu3", "1,3\n")];
+        for &(cpu, list) in &siblings {
+            fs::create_dir_all(dir.join(cpu).join("topology")).unwrap();
+            fs::write(dir.join(cpu).join("topology").join("thread_siblings_list"), list).unwrap();
+        }
+        fs::write(dir.join("online"), "0-2\n").unwrap();
+
+        let cpus = |pinning: Pinning, allowed: &[usize]| pinning.cpus_from_dir(&dir, allowed);
+        let results = (
+            cpus(Pinning::Off, &[]).unwrap(),
+            cpus(Pinning::Cores, &[]).unwrap(),
+            cpus(Pinning::Threads, &[]).unwrap(),
+            cpus(Pinning::Cores, &[1, 2]).unwrap(),
+            cpus(Pinning::Threads, &[0, 1, 2]).unwrap(),
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!((vec![], vec![0, 1], vec![0, 1, 2], vec![1, 2], vec![0, 1, 2]), results);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn pinning_affinity() {
+        let usable = affinity().unwrap();
+        for &pinning in &[Pinning::Cores, Pinning::Threads] {
+            let cpus = pinning.cpus(&[]).unwrap();
+            assert!(!cpus.is_empty());
+            assert!(cpus.iter().all(|cpu| usable.contains(cpu)));
+            check_pinning(&[cpus]).unwrap();
+        }
+        assert!(Pinning::Cores.cpus(&[usize::max_value()]).is_err());
+    }
+
+    #[test]
+    fn detect() {
+        assert!(!Topology::detect().nodes().is_empty());
+    }
+
+    fn check(topology: &Topology, pinning: Pinning) {
+        let dist = NumaDistribute::with_pinning(topology, Cutoff(8), pinning).unwrap();
+        for shift in 0..5 {
+            let size = 4 << shift;
+            let a = Simple::random(size, size);
+            let b = Simple::random(size, size);
+            let a_z = dist.convert::<U4>(&a);
+            let b_z = dist.convert::<U4>(&b);
+            assert_eq!(ZMat::<U4>::from(&a), a_z);
+            let r_z = znot::multiply::<_, _, SimdMultiplyAdd>(&dist, &a_z, &b_z);
+            Simple::from(&r_z).assert_approx(&simple::multiply(&a, &b));
+        }
+    }
+
+    #[test]
+    fn single() {
+        check(&Topology::detect(), Pinning::Off);
+    }
+
+    // The CPU topology comes from the Linux sysfs
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn pinned() {
+        check(&Topology::detect(), Pinning::Cores);
+        check(&Topology::detect(), Pinning::Threads);
+    }
+
+    #[test]
+    fn multiple() {
+        // Pretend there are more nodes, without pinning to any CPUs
+        let topology = Topology {
+            nodes: (0..3).map(|id| Node { id, cpus: Vec::new() }).collect(),
+        };
+        check(&topology, Pinning::Off);
+    }
+}
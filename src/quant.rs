@@ -0,0 +1,386 @@
+use std::num::Wrapping;
+
+use typenum::Unsigned;
+
+use super::simple::Matrix as Simple;
+use super::znot::{self, Distribute};
+
+/// A square matrix of (quantized) integers in the Z-order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Matrix<Frag: Unsigned, E> {
+    _frag: Frag,
+    size: usize,
+    content: Vec<E>,
+}
+
+impl<Frag: Unsigned + Default, E: Copy> Matrix<Frag, E> {
+    /// Converts from a row-major representation of a size×size matrix.
+    pub fn from_rows(size: usize, rows: &[E]) -> Self {
+        assert_eq!(rows.len(), size * size);
+        Self {
+            _frag: Frag::default(),
+            size,
+            content: znot::z_order(size, Frag::USIZE, |x, y| rows[x + y * size]),
+        }
+    }
+}
+
+impl<Frag: Unsigned, E: Copy + Default> Matrix<Frag, E> {
+    pub fn to_rows(&self) -> Vec<E> {
+        let size = self.size;
+        let mut result = vec![E::default(); size * size];
+        znot::row_major(&self.content, size, Frag::USIZE, |x, y, val| result[x + y * size] = val);
+        result
+    }
+    pub fn size(&self) -> usize { self.size }
+}
+
+/// Parameters of the affine quantization.
+///
+/// The real value is `scale * (q - zero_point)`. For the left matrix, there's one zero point and
+/// scale per row, for the right one per column.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Quantization {
+    pub zero_points: Vec<i32>,
+    pub scales: Vec<f32>,
+}
+
+/// Exact multiplication of fragments of unsigned and signed bytes, `r += a * bᵀ`.
+///
+/// Each row of `bt` is a column of `b`. The sums are exact as long as they fit into i32, which is
+/// guaranteed for matrices smaller than 65793.
+pub trait FragMultiplyAdd {
+    fn multiply_add_transposed(r: &mut [i32], a: &[u8], bt: &[i8], size: usize);
+}
+
+fn dot(a: &[u8], b: &[i8]) -> i32 {
+    a.iter()
+        .zip(b)
+        .map(|(&a, &b)| i32::from(a) * i32::from(b))
+        .sum()
+}
+
+fn multiply_add_by(r: &mut [i32], a: &[u8], bt: &[i8], size: usize, dot: fn(&[u8], &[i8]) -> i32) {
+    for y in 0..size {
+        let row = &a[y * size .. (y + 1) * size];
+        for x in 0..size {
+            r[y * size + x] += dot(row, &bt[x * size .. (x + 1) * size]);
+        }
+    }
+}
+
+pub struct ScalarMultiplyAdd;
+
+impl FragMultiplyAdd for ScalarMultiplyAdd {
+    fn multiply_add_transposed(r: &mut [i32], a: &[u8], bt: &[i8], size: usize) {
+        multiply_add_by(r, a, bt, size, dot);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    // The pmaddubsw instruction would multiply the bytes directly, but it saturates the i16
+    // results of the pairs and we want the results to be exact. Therefore the bytes are widened to
+    // i16 first and multiplied by pmaddwd, which sums the pairs to i32.
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn dot(a: &[u8], b: &[i8]) -> i32 {
+        let len = a.len().min(b.len());
+        let chunks = len / 16;
+        let mut acc = _mm256_setzero_si256();
+        for i in 0..chunks {
+            let va = _mm_loadu_si128(a.as_ptr().add(i * 16) as *const __m128i);
+            let vb = _mm_loadu_si128(b.as_ptr().add(i * 16) as *const __m128i);
+            let wa = _mm256_cvtepu8_epi16(va);
+            let wb = _mm256_cvtepi8_epi16(vb);
+            acc = _mm256_add_epi32(acc, _mm256_madd_epi16(wa, wb));
+        }
+        let mut lanes = [0i32; 8];
+        _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, acc);
+        let tail = super::dot(&a[chunks * 16 .. len], &b[chunks * 16 .. len]);
+        lanes.iter().sum::<i32>() + tail
+    }
+}
+
+// The best dot product the CPU supports. The detection caches its result, so the fragments can
+// ask each time.
+#[cfg(target_arch = "x86_64")]
+fn detect_dot() -> fn(&[u8], &[i8]) -> i32 {
+    fn avx2(a: &[u8], b: &[i8]) -> i32 {
+        // Checked by the caller below
+        unsafe { x86::dot(a, b) }
+    }
+
+    if is_x86_feature_detected!("avx2") {
+        avx2
+    } else {
+        dot
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn detect_dot() -> fn(&[u8], &[i8]) -> i32 {
+    dot
+}
+
+/// Uses the widening multiply-add instructions, if the CPU supports them.
+///
+/// There's no vpdpbusd (AVX-512 VNNI) path, even though the instruction would sum the products of
+/// four byte pairs into i32 without saturating. Neither the intrinsic nor its runtime detection is
+/// available in our toolchain yet, so only the AVX2 path is used.
+pub struct WideMultiplyAdd;
+
+impl FragMultiplyAdd for WideMultiplyAdd {
+    fn multiply_add_transposed(r: &mut [i32], a: &[u8], bt: &[i8], size: usize) {
+        multiply_add_by(r, a, bt, size, detect_dot());
+    }
+}
+
+/// The raw product of the quantized values, without taking the zero points into account.
+pub fn multiply<Frag, Dist, Mult>(dist: &Dist, a: &Matrix<Frag, u8>, b: &Matrix<Frag, i8>)
+    -> Matrix<Frag, i32>
+where
+    Frag: Unsigned + Default,
+    Dist: Distribute,
+    Mult: FragMultiplyAdd,
+{
+    assert_eq!(a.size, b.size);
+    let mut result = Matrix {
+        _frag: Frag::default(),
+        size: a.size,
+        content: vec![0; a.size * a.size],
+    };
+
+    // Transposed once here, instead of in each of the fragments
+    let mut bt = vec![0; b.size * b.size];
+    znot::transpose_into(&mut bt, &b.content, b.size, Frag::USIZE);
+    znot::recurse_transposed(
+        dist,
+        &mut result.content,
+        &a.content,
+        &bt,
+        a.size,
+        Frag::USIZE,
+        &Mult::multiply_add_transposed,
+    );
+
+    result
+}
+
+/// Takes the zero points into account, returning the row-major exact product of
+/// `(a - a_zero) * (b - b_zero)`.
+///
+/// The `a_zero` has one zero point for each row of `a`, `b_zero` for each column of `b`. The
+/// result is exact whenever it fits into i32, even if the zero points are large.
+pub fn centered<Frag: Unsigned>(
+    product: &Matrix<Frag, i32>,
+    a: &Matrix<Frag, u8>,
+    a_zero: &[i32],
+    b: &Matrix<Frag, i8>,
+    b_zero: &[i32],
+) -> Vec<i32> {
+    let size = product.size;
+    assert_eq!(size, a.size);
+    assert_eq!(size, b.size);
+    assert_eq!(size, a_zero.len());
+    assert_eq!(size, b_zero.len());
+
+    // Σ(a - za)(b - zb) = Σab - zb·Σa - za·Σb + k·za·zb
+    //
+    // The terms can overflow on their own while the result still fits. Everything wraps, so they
+    // cancel out modulo 2³² and the result comes out exact.
+    let a_rows = a.to_rows();
+    let row_sums = a_rows
+        .chunks(size)
+        .map(|row| row.iter().map(|&v| Wrapping(i32::from(v))).sum::<Wrapping<i32>>())
+        .collect::<Vec<_>>();
+    let mut col_sums = vec![Wrapping(0i32); size];
+    for row in b.to_rows().chunks(size) {
+        for (sum, &v) in col_sums.iter_mut().zip(row) {
+            *sum += Wrapping(i32::from(v));
+        }
+    }
+
+    let mut result = product.to_rows();
+    let k = Wrapping(size as i32);
+    for y in 0..size {
+        let za = Wrapping(a_zero[y]);
+        for x in 0..size {
+            let zb = Wrapping(b_zero[x]);
+            let val = &mut result[x + y * size];
+            *val = (Wrapping(*val) - zb * row_sums[y] - za * col_sums[x] + k * za * zb).0;
+        }
+    }
+    result
+}
+
+/// Converts the raw product back to real numbers.
+pub fn dequantize<Frag: Unsigned>(
+    product: &Matrix<Frag, i32>,
+    a: &Matrix<Frag, u8>,
+    a_params: &Quantization,
+    b: &Matrix<Frag, i8>,
+    b_params: &Quantization,
+) -> Simple {
+    let size = product.size;
+    assert_eq!(size, a_params.scales.len());
+    assert_eq!(size, b_params.scales.len());
+
+    let centered = centered(product, a, &a_params.zero_points, b, &b_params.zero_points);
+    let mut result = Simple::sized(size, size);
+    for y in 0..size {
+        for x in 0..size {
+            let scale = a_params.scales[y] * b_params.scales[x];
+            result[(x, y)] = scale * centered[x + y * size] as f32;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{self, Rng};
+    use typenum::{U1, U4, U16, U32};
+
+    use ::znot::{DontDistribute, RayonDistribute};
+
+    fn random<T: ::rand::Rand>(size: usize) -> Vec<T> {
+        let mut rng = rand::thread_rng();
+        (0..size * size).map(|_| rng.gen()).collect()
+    }
+
+    fn reference(a: &[u8], b: &[i8], size: usize) -> Vec<i32> {
+        let mut result = vec![0; size * size];
+        for y in 0..size {
+            for p in 0..size {
+                let av = i32::from(a[y * size + p]);
+                for x in 0..size {
+                    result[y * size + x] += av * i32::from(b[p * size + x]);
+                }
+            }
+        }
+        result
+    }
+
+    // In i64, so nothing overflows on the way
+    fn centered_reference(a: &[u8], a_zero: &[i32], b: &[i8], b_zero: &[i32], size: usize)
+        -> Vec<i32>
+    {
+        let mut result = vec![0; size * size];
+        for y in 0..size {
+            for x in 0..size {
+                let sum = (0..size)
+                    .map(|p| {
+                        let av = i64::from(a[p + y * size]) - i64::from(a_zero[y]);
+                        let bv = i64::from(b[x + p * size]) - i64::from(b_zero[x]);
+                        av * bv
+                    })
+                    .sum::<i64>();
+                assert!(sum >= i64::from(i32::min_value()) && sum <= i64::from(i32::max_value()));
+                result[x + y * size] = sum as i32;
+            }
+        }
+        result
+    }
+
+    fn test_multi<Frag: Unsigned + Default, Mult: FragMultiplyAdd>() {
+        for shift in 0..4 {
+            let size = Frag::USIZE << shift;
+            let a = random::<u8>(size);
+            let b = random::<i8>(size);
+            let expected = reference(&a, &b, size);
+            let a_z = Matrix::<Frag, _>::from_rows(size, &a);
+            let b_z = Matrix::<Frag, _>::from_rows(size, &b);
+            let r_z = multiply::<_, _, Mult>(&DontDistribute, &a_z, &b_z);
+            assert_eq!(expected, r_z.to_rows());
+            let r_z = multiply::<_, _, Mult>(&RayonDistribute(U32::new()), &a_z, &b_z);
+            assert_eq!(expected, r_z.to_rows());
+        }
+    }
+
+    #[test]
+    fn there_and_back() {
+        let a = random::<u8>(16);
+        assert_eq!(a, Matrix::<U4, _>::from_rows(16, &a).to_rows());
+    }
+
+    #[test]
+    fn scalar_1() {
+        test_multi::<U1, ScalarMultiplyAdd>();
+    }
+
+    #[test]
+    fn scalar_16() {
+        test_multi::<U16, ScalarMultiplyAdd>();
+    }
+
+    #[test]
+    fn wide_1() {
+        test_multi::<U1, WideMultiplyAdd>();
+    }
+
+    #[test]
+    fn wide_4() {
+        test_multi::<U4, WideMultiplyAdd>();
+    }
+
+    #[test]
+    fn wide_32() {
+        test_multi::<U32, WideMultiplyAdd>();
+    }
+
+    #[test]
+    fn zero_points() {
+        let size = 32;
+        let a = random::<u8>(size);
+        let b = random::<i8>(size);
+        let mut rng = rand::thread_rng();
+        let a_zero = (0..size).map(|_| rng.gen_range(0, 256)).collect::<Vec<i32>>();
+        let b_zero = (0..size).map(|_| rng.gen_range(-128, 128)).collect::<Vec<i32>>();
+
+        let expected = centered_reference(&a, &a_zero, &b, &b_zero, size);
+
+        let a_z = Matrix::<U4, _>::from_rows(size, &a);
+        let b_z = Matrix::<U4, _>::from_rows(size, &b);
+        let product = multiply::<_, _, WideMultiplyAdd>(&DontDistribute, &a_z, &b_z);
+        assert_eq!(expected, centered(&product, &a_z, &a_zero, &b_z, &b_zero));
+
+        let a_params = Quantization {
+            zero_points: a_zero,
+            scales: vec![0.5; size],
+        };
+        let b_params = Quantization {
+            zero_points: b_zero,
+            scales: vec![0.25; size],
+        };
+        let real = dequantize(&product, &a_z, &a_params, &b_z, &b_params);
+        for y in 0..size {
+            for x in 0..size {
+                assert_eq!(expected[x + y * size] as f32 / 8.0, real[(x, y)]);
+            }
+        }
+    }
+
+    #[test]
+    fn large_zero_points() {
+        // k·za·zb and za·Σb overflow i32, but the b values sit right at their zero points and
+        // the results fit.
+        let size = 32;
+        let mut rng = rand::thread_rng();
+        let a = random::<u8>(size);
+        let a_zero = (0..size).map(|_| (1 << 20) + rng.gen_range(0, 256)).collect::<Vec<i32>>();
+        let b_zero = (0..size).map(|_| rng.gen_range(100, 127)).collect::<Vec<i32>>();
+        let b = (0..size * size)
+            .map(|i| (b_zero[i % size] + rng.gen_range(0, 2)) as i8)
+            .collect::<Vec<_>>();
+        let expected = centered_reference(&a, &a_zero, &b, &b_zero, size);
+
+        let a_z = Matrix::<U4, _>::from_rows(size, &a);
+        let b_z = Matrix::<U4, _>::from_rows(size, &b);
+        let product = multiply::<_, _, WideMultiplyAdd>(&DontDistribute, &a_z, &b_z);
+        assert_eq!(expected, centered(&product, &a_z, &a_zero, &b_z, &b_zero));
+    }
+}
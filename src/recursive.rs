@@ -0,0 +1,212 @@
+use super::Element;
+use super::simple::Matrix;
+use super::znot::{Distribute, FragMultiplyAdd};
+
+// A rectangular part of a row-major matrix. Unlike Slice, the rows don't have to be adjacent.
+#[derive(Clone, Copy)]
+pub(crate) struct View<'a> {
+    pub(crate) content: &'a [Element],
+    pub(crate) stride: usize,
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+}
+
+impl<'a> View<'a> {
+    fn split_rows(self, at: usize) -> (View<'a>, View<'a>) {
+        let top = View {
+            height: at,
+            ..self
+        };
+        let bottom = View {
+            content: &self.content[at * self.stride..],
+            height: self.height - at,
+            ..self
+        };
+        (top, bottom)
+    }
+    fn split_cols(self, at: usize) -> (View<'a>, View<'a>) {
+        let left = View {
+            width: at,
+            ..self
+        };
+        let right = View {
+            content: &self.content[at..],
+            width: self.width - at,
+            ..self
+        };
+        (left, right)
+    }
+}
+
+pub(crate) struct ViewMut<'a> {
+    pub(crate) content: &'a mut [Element],
+    pub(crate) stride: usize,
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+}
+
+impl<'a> ViewMut<'a> {
+    // Rows can be split into two independent parts (which can be used from different threads).
+    fn split_rows(&mut self, at: usize) -> (ViewMut, ViewMut) {
+        let (top, bottom) = self.content.split_at_mut(at * self.stride);
+        let top = ViewMut {
+            content: top,
+            stride: self.stride,
+            width: self.width,
+            height: at,
+        };
+        let bottom = ViewMut {
+            content: bottom,
+            stride: self.stride,
+            width: self.width,
+            height: self.height - at,
+        };
+        (top, bottom)
+    }
+    // Columns interleave in the memory, so we can have only one of them at a time.
+    fn cols(&mut self, x: usize, w: usize) -> ViewMut {
+        ViewMut {
+            content: &mut self.content[x..],
+            stride: self.stride,
+            width: w,
+            height: self.height,
+        }
+    }
+}
+
+fn leaf<Mult: FragMultiplyAdd>(r: &mut ViewMut, a: View, b: View) {
+    // The kernel works right on the views, so the (possibly ragged) pieces are neither copied nor
+    // padded.
+    let shape = (r.height, a.width, r.width);
+    let (r, r_stride) = (&mut *r.content, r.stride);
+    Mult::multiply_add_strided(r, r_stride, a.content, a.stride, b.content, b.stride, shape);
+}
+
+fn recurse<Dist, Mult>(dist: &Dist, r: &mut ViewMut, a: View, b: View, limit: usize)
+where
+    Dist: Distribute,
+    Mult: FragMultiplyAdd,
+{
+    let (m, k, n) = (r.height, a.width, r.width);
+    if m <= limit && k <= limit && n <= limit {
+        leaf::<Mult>(r, a, b);
+    } else if m >= k && m >= n {
+        // Halves of the rows are independent, so these may run in parallel.
+        let h = m / 2;
+        let (a1, a2) = a.split_rows(h);
+        let (r1, r2) = r.split_rows(h);
+        let mut tasks = [(r1, a1), (r2, a2)];
+        dist.run(m, &mut tasks, |&mut (ref mut r, a)| {
+            recurse::<_, Mult>(dist, r, a, b, limit);
+        });
+    } else if n >= k {
+        let w = n / 2;
+        let (b1, b2) = b.split_cols(w);
+        recurse::<_, Mult>(dist, &mut r.cols(0, w), a, b1, limit);
+        recurse::<_, Mult>(dist, &mut r.cols(w, n - w), a, b2, limit);
+    } else {
+        // Both halves of k write into the same result, so they need to go one after another.
+        let h = k / 2;
+        let (a1, a2) = a.split_cols(h);
+        let (b1, b2) = b.split_rows(h);
+        recurse::<_, Mult>(dist, r, a1, b1, limit);
+        recurse::<_, Mult>(dist, r, a2, b2, limit);
+    }
+}
+
+// r += a * b right in the (parts of) bigger matrices, without copying them anywhere.
+pub(crate) fn multiply_add<Dist, Mult>(dist: &Dist, r: &mut ViewMut, a: View, b: View, limit: usize)
+where
+    Dist: Distribute,
+    Mult: FragMultiplyAdd,
+{
+    assert_eq!((a.height, a.width, b.width), (r.height, b.height, r.width));
+    if r.width == 0 || r.height == 0 || a.width == 0 {
+        return;
+    }
+    recurse::<_, Mult>(dist, r, a, b, limit.max(1));
+}
+
+/// Cache-oblivious multiplication directly on the row-major matrices.
+///
+/// The largest of the dimensions is split in half until all of them get to `limit` or below, then
+/// the fragment kernel takes over. This works on any shape and needs no conversion to the Z-order.
+pub fn multiply<Dist, Mult>(dist: &Dist, a: &Matrix, b: &Matrix, limit: usize) -> Matrix
+where
+    Dist: Distribute,
+    Mult: FragMultiplyAdd,
+{
+    assert_eq!(a.width(), b.height());
+    let mut result = Matrix::sized(b.width(), a.height());
+
+    let (a, b) = (a.slice(), b.slice());
+    let a = View {
+        content: a.content,
+        stride: a.width,
+        width: a.width,
+        height: a.height,
+    };
+    let b = View {
+        content: b.content,
+        stride: b.width,
+        width: b.width,
+        height: b.height,
+    };
+    let r = result.slice_mut();
+    let mut r = ViewMut {
+        content: r.content,
+        stride: r.width,
+        width: r.width,
+        height: r.height,
+    };
+    multiply_add::<_, Mult>(dist, &mut r, a, b, limit);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use typenum::U32;
+
+    use ::simple::tests::check_shapes;
+    use ::znot::{
+        CompensatedMultiplyAdd, DontDistribute, MixedMultiplyAdd, RayonDistribute,
+        SimdMultiplyAdd, SimpleMultiplyAdd,
+    };
+
+    fn test_shapes<Dist: Distribute, Mult: FragMultiplyAdd>(dist: &Dist, limit: usize) {
+        // Right at the limit, and one over it in each of the dimensions
+        let extra = [
+            (limit, limit, limit),
+            (limit + 1, limit, limit),
+            (limit, limit + 1, limit),
+            (limit, limit, limit + 1),
+        ];
+        check_shapes(&extra, |a, b, expected| {
+            multiply::<_, Mult>(dist, a, b, limit).assert_approx(expected);
+        });
+    }
+
+    #[test]
+    fn simple_1() {
+        test_shapes::<_, SimpleMultiplyAdd>(&DontDistribute, 1);
+    }
+
+    #[test]
+    fn simple_7() {
+        test_shapes::<_, SimpleMultiplyAdd>(&DontDistribute, 7);
+    }
+
+    #[test]
+    fn compensated_mixed_7() {
+        test_shapes::<_, CompensatedMultiplyAdd>(&DontDistribute, 7);
+        test_shapes::<_, MixedMultiplyAdd>(&DontDistribute, 7);
+    }
+
+    #[test]
+    fn simd_paral_16() {
+        test_shapes::<_, SimdMultiplyAdd>(&RayonDistribute(U32::new()), 16);
+    }
+}
@@ -0,0 +1,445 @@
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::ops::{Index, IndexMut};
+
+use faster::prelude::*;
+use typenum::Unsigned;
+
+use super::Element;
+use super::simple::{self, Matrix as Simple, Slice, SliceMut};
+use super::znot::{self, Distribute, SimpleMultiplyAdd};
+
+/// The operations the classical multiplication needs instead of the usual `+` and `*`.
+///
+/// With the min-plus (tropical) semiring, the product of two distance matrices gives the shortest
+/// paths with one more step, so repeated squaring computes all-pairs shortest paths. Similarly,
+/// max-plus gives the longest paths and the boolean semiring the reachability.
+pub trait Semiring {
+    type Element: Copy + Debug + PartialEq + Send + Sync;
+    /// The neutral element of `add` (and the absorbing one of `mul`).
+    fn zero() -> Self::Element;
+    /// The neutral element of `mul`.
+    fn one() -> Self::Element;
+    fn add(a: Self::Element, b: Self::Element) -> Self::Element;
+    fn mul(a: Self::Element, b: Self::Element) -> Self::Element;
+    /// `r = a + b` for whole blocks, element by element.
+    ///
+    /// The default goes one element at a time, semirings with SIMD support may do better.
+    fn add_elements(r: &mut [Self::Element], a: &[Self::Element], b: &[Self::Element]) {
+        for ((r, &a), &b) in r.iter_mut().zip(a).zip(b) {
+            *r = Self::add(a, b);
+        }
+    }
+}
+
+/// A semiring with subtraction.
+pub trait Ring: Semiring {
+    fn sub(a: Self::Element, b: Self::Element) -> Self::Element;
+    /// `r = a - b` for whole blocks, like `add_elements`.
+    fn sub_elements(r: &mut [Self::Element], a: &[Self::Element], b: &[Self::Element]) {
+        for ((r, &a), &b) in r.iter_mut().zip(a).zip(b) {
+            *r = Self::sub(a, b);
+        }
+    }
+}
+
+/// The usual `+` and `*`, on `f32` unless asked for `f64`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Arithmetic<T = Element>(PhantomData<T>);
+
+impl Semiring for Arithmetic {
+    type Element = Element;
+    fn zero() -> Element { 0. }
+    fn one() -> Element { 1. }
+    fn add(a: Element, b: Element) -> Element { a + b }
+    fn mul(a: Element, b: Element) -> Element { a * b }
+    fn add_elements(r: &mut [Element], a: &[Element], b: &[Element]) {
+        (a.simd_iter(f32s(0.)), b.simd_iter(f32s(0.))).zip()
+            .simd_map(|(a, b)| a + b)
+            .scalar_fill(r);
+    }
+}
+
+impl Ring for Arithmetic {
+    fn sub(a: Element, b: Element) -> Element { a - b }
+    fn sub_elements(r: &mut [Element], a: &[Element], b: &[Element]) {
+        (a.simd_iter(f32s(0.)), b.simd_iter(f32s(0.))).zip()
+            .simd_map(|(a, b)| a - b)
+            .scalar_fill(r);
+    }
+}
+
+impl Semiring for Arithmetic<f64> {
+    type Element = f64;
+    fn zero() -> f64 { 0. }
+    fn one() -> f64 { 1. }
+    fn add(a: f64, b: f64) -> f64 { a + b }
+    fn mul(a: f64, b: f64) -> f64 { a * b }
+}
+
+impl Ring for Arithmetic<f64> {
+    fn sub(a: f64, b: f64) -> f64 { a - b }
+}
+
+/// `min` as the addition and `+` as the multiplication (infinity stands for no edge).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MinPlus;
+
+impl Semiring for MinPlus {
+    type Element = Element;
+    fn zero() -> Element { ::std::f32::INFINITY }
+    fn one() -> Element { 0. }
+    fn add(a: Element, b: Element) -> Element { a.min(b) }
+    fn mul(a: Element, b: Element) -> Element { a + b }
+}
+
+/// `max` as the addition and `+` as the multiplication (negative infinity stands for no edge).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MaxPlus;
+
+impl Semiring for MaxPlus {
+    type Element = Element;
+    fn zero() -> Element { ::std::f32::NEG_INFINITY }
+    fn one() -> Element { 0. }
+    fn add(a: Element, b: Element) -> Element { a.max(b) }
+    fn mul(a: Element, b: Element) -> Element { a + b }
+}
+
+/// `or` as the addition and `and` as the multiplication.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Boolean;
+
+impl Semiring for Boolean {
+    type Element = bool;
+    fn zero() -> bool { false }
+    fn one() -> bool { true }
+    fn add(a: bool, b: bool) -> bool { a || b }
+    fn mul(a: bool, b: bool) -> bool { a && b }
+}
+
+/// A row-major matrix over a semiring.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Matrix<S: Semiring> {
+    _semiring: PhantomData<S>,
+    width: usize,
+    height: usize,
+    content: Vec<S::Element>,
+}
+
+impl<S: Semiring> Matrix<S> {
+    /// A matrix full of the semiring's zero.
+    pub fn sized(w: usize, h: usize) -> Self {
+        Self {
+            _semiring: PhantomData,
+            width: w,
+            height: h,
+            content: vec![S::zero(); w * h],
+        }
+    }
+    pub fn identity(size: usize) -> Self {
+        let mut result = Self::sized(size, size);
+        for i in 0..size {
+            result[(i, i)] = S::one();
+        }
+        result
+    }
+    pub fn height(&self) -> usize { self.height }
+    pub fn width(&self) -> usize { self.width }
+}
+
+impl<S: Semiring> Index<(usize, usize)> for Matrix<S> {
+    type Output = S::Element;
+    fn index(&self, index: (usize, usize)) -> &S::Element {
+        &self.content[index.0 + self.width * index.1]
+    }
+}
+
+impl<S: Semiring> IndexMut<(usize, usize)> for Matrix<S> {
+    fn index_mut(&mut self, index: (usize, usize)) -> &mut S::Element {
+        &mut self.content[index.0 + self.width * index.1]
+    }
+}
+
+impl<'a, S: Semiring<Element = Element>> From<&'a Simple> for Matrix<S> {
+    fn from(matrix: &'a Simple) -> Self {
+        Self {
+            _semiring: PhantomData,
+            width: matrix.width(),
+            height: matrix.height(),
+            content: matrix.rows().flat_map(|row| row.iter().cloned()).collect(),
+        }
+    }
+}
+
+impl<'a, S: Semiring<Element = Element>> From<&'a Matrix<S>> for Simple {
+    fn from(matrix: &'a Matrix<S>) -> Self {
+        let mut result = Simple::sized(matrix.width, matrix.height);
+        for y in 0..matrix.height {
+            for x in 0..matrix.width {
+                result[(x, y)] = matrix[(x, y)];
+            }
+        }
+        result
+    }
+}
+
+pub fn multiply<S: Semiring>(a: &Matrix<S>, b: &Matrix<S>) -> Matrix<S> {
+    assert_eq!(a.width, b.height);
+
+    let mut r = Matrix::sized(b.width, a.height);
+    simple::multiply_add_in::<S>(
+        &mut SliceMut {
+            width: r.width,
+            height: r.height,
+            content: &mut r.content,
+        },
+        &Slice {
+            width: a.width,
+            height: a.height,
+            content: &a.content,
+        },
+        &Slice {
+            width: b.width,
+            height: b.height,
+            content: &b.content,
+        },
+    );
+    r
+}
+
+/// A square matrix over a semiring, in the Z-order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZOrder<Frag: Unsigned, S: Semiring> {
+    _frag: Frag,
+    _semiring: PhantomData<S>,
+    size: usize,
+    content: Vec<S::Element>,
+}
+
+impl<Frag: Unsigned, S: Semiring> ZOrder<Frag, S> {
+    pub fn size(&self) -> usize { self.size }
+}
+
+impl<'a, Frag: Unsigned + Default, S: Semiring> From<&'a Matrix<S>> for ZOrder<Frag, S> {
+    fn from(matrix: &'a Matrix<S>) -> Self {
+        let size = matrix.width;
+
+        assert_eq!(matrix.width, matrix.height, "We support only square matrices");
+
+        Self {
+            _frag: Frag::default(),
+            _semiring: PhantomData,
+            size,
+            content: znot::z_order(size, Frag::USIZE, |x, y| matrix[(x, y)]),
+        }
+    }
+}
+
+impl<'a, Frag: Unsigned, S: Semiring> From<&'a ZOrder<Frag, S>> for Matrix<S> {
+    fn from(matrix: &'a ZOrder<Frag, S>) -> Self {
+        let mut result = Matrix::sized(matrix.size, matrix.size);
+        znot::row_major(&matrix.content, matrix.size, Frag::USIZE, |x, y, val| {
+            result[(x, y)] = val
+        });
+        result
+    }
+}
+
+/// The classical recursive multiplication (like [`znot::multiply`](../znot/fn.multiply.html)).
+pub fn z_multiply<Frag, Dist, S>(dist: &Dist, a: &ZOrder<Frag, S>, b: &ZOrder<Frag, S>)
+    -> ZOrder<Frag, S>
+where
+    Frag: Unsigned + Default,
+    Dist: Distribute,
+    S: Semiring,
+{
+    assert_eq!(a.size, b.size);
+    let size = a.size;
+    let mut result = ZOrder {
+        _frag: Frag::default(),
+        _semiring: PhantomData,
+        size,
+        content: vec![S::zero(); size * size],
+    };
+
+    let r = &mut result.content;
+    znot::mult_add::<_, S, SimpleMultiplyAdd>(dist, r, &a.content, &b.content, size, Frag::USIZE);
+
+    result
+}
+
+/// Strassen multiplication, switching to the classical recursion at `cutoff` size (like
+/// [`znot::hybrid_strassen`](../znot/fn.hybrid_strassen.html)).
+///
+/// Available only for rings, since Strassen needs subtraction. Asking for it with a semiring
+/// without one fails to compile:
+///
+/// ```compile_fail
+/// # extern crate fastmatmult;
+/// # extern crate typenum;
+/// # use fastmatmult::semiring::{self, MinPlus, ZOrder};
+/// # use fastmatmult::znot::DontDistribute;
+/// # use typenum::U4;
+/// # fn main() {
+/// let a = ZOrder::<U4, MinPlus>::from(&semiring::Matrix::identity(8));
+/// semiring::strassen(&DontDistribute, &a, &a, 4);
+/// # }
+/// ```
+///
+/// With a ring, it does:
+///
+/// ```
+/// # extern crate fastmatmult;
+/// # extern crate typenum;
+/// # use fastmatmult::semiring::{self, Arithmetic, ZOrder};
+/// # use fastmatmult::znot::DontDistribute;
+/// # use typenum::U4;
+/// # fn main() {
+/// let a = ZOrder::<U4, Arithmetic>::from(&semiring::Matrix::identity(8));
+/// assert_eq!(a, semiring::strassen(&DontDistribute, &a, &a, 4));
+/// # }
+/// ```
+pub fn strassen<Frag, Dist, S>(
+    dist: &Dist,
+    a: &ZOrder<Frag, S>,
+    b: &ZOrder<Frag, S>,
+    cutoff: usize,
+) -> ZOrder<Frag, S>
+where
+    Frag: Unsigned + Default,
+    Dist: Distribute,
+    S: Ring,
+{
+    assert_eq!(a.size, b.size);
+    let size = a.size;
+    let cutoff = cutoff.max(Frag::USIZE);
+    let mut result = ZOrder {
+        _frag: Frag::default(),
+        _semiring: PhantomData,
+        size,
+        content: vec![S::zero(); size * size],
+    };
+
+    let (r, frag) = (&mut result.content, Frag::USIZE);
+    znot::step::<_, S, SimpleMultiplyAdd>(dist, r, &a.content, &b.content, size, frag, cutoff);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use typenum::{U1, U2, U4, U32};
+
+    use ::simple;
+    use ::znot::{DontDistribute, RayonDistribute};
+
+    const INF: Element = ::std::f32::INFINITY;
+
+    fn graph(size: usize) -> Matrix<MinPlus> {
+        // A cycle with some chords, the weights are small integers so the sums are exact.
+        let mut result = Matrix::identity(size);
+        for i in 0..size {
+            result[((i + 1) % size, i)] = (i % 3 + 1) as Element;
+            result[((i * 7 + 3) % size, i)] = (i % 5 + 4) as Element;
+        }
+        result
+    }
+
+    fn floyd_warshall(graph: &Matrix<MinPlus>) -> Matrix<MinPlus> {
+        let mut dist = graph.clone();
+        let n = graph.width();
+        for k in 0..n {
+            for i in 0..n {
+                for j in 0..n {
+                    let through = dist[(k, i)] + dist[(j, k)];
+                    if through < dist[(j, i)] {
+                        dist[(j, i)] = through;
+                    }
+                }
+            }
+        }
+        dist
+    }
+
+    #[test]
+    fn shortest_paths() {
+        let size = 32;
+        let g = graph(size);
+        let expected = floyd_warshall(&g);
+
+        // Paths of length up to 2^5 = size
+        let mut dist = g.clone();
+        let mut dist_z = ZOrder::<U4, MinPlus>::from(&g);
+        for _ in 0..5 {
+            dist = multiply(&dist, &dist);
+            dist_z = z_multiply(&RayonDistribute(U32::new()), &dist_z, &dist_z);
+        }
+        assert_eq!(expected, dist);
+        assert_eq!(expected, Matrix::from(&dist_z));
+    }
+
+    #[test]
+    fn unreachable() {
+        let mut g = Matrix::<MinPlus>::identity(2);
+        g[(1, 0)] = 3.;
+        let dist = multiply(&g, &g);
+        assert_eq!(3., dist[(1, 0)]);
+        assert_eq!(INF, dist[(0, 1)]);
+    }
+
+    #[test]
+    fn longest_paths() {
+        // A DAG 0 -> 1 -> 2 and a shortcut 0 -> 2
+        let mut g = Matrix::<MaxPlus>::identity(4);
+        g[(1, 0)] = 2.;
+        g[(2, 1)] = 3.;
+        g[(2, 0)] = 4.;
+        let paths = multiply(&g, &g);
+        assert_eq!(5., paths[(2, 0)]);
+        assert_eq!(::std::f32::NEG_INFINITY, paths[(3, 0)]);
+    }
+
+    #[test]
+    fn reachability() {
+        let size = 16;
+        let mut g = Matrix::<Boolean>::identity(size);
+        // Two separate chains, the even and the odd vertices
+        for i in 0..size - 2 {
+            g[(i + 2, i)] = true;
+        }
+        let mut reach = ZOrder::<U2, Boolean>::from(&g);
+        for _ in 0..3 {
+            reach = z_multiply(&DontDistribute, &reach, &reach);
+        }
+        let reach = Matrix::from(&reach);
+        for y in 0..size {
+            for x in 0..size {
+                assert_eq!(x >= y && (x - y) % 2 == 0, reach[(x, y)], "{} -> {}", y, x);
+            }
+        }
+    }
+
+    #[test]
+    fn arithmetic() {
+        let size = 32;
+        let a = Simple::random(size, size);
+        let b = Simple::random(size, size);
+        let expected = simple::multiply(&a, &b);
+
+        let a_s = Matrix::<Arithmetic>::from(&a);
+        let b_s = Matrix::<Arithmetic>::from(&b);
+        Simple::from(&multiply(&a_s, &b_s)).assert_approx(&expected);
+
+        let a_z = ZOrder::<U1, Arithmetic>::from(&a_s);
+        let b_z = ZOrder::<U1, Arithmetic>::from(&b_s);
+        let paral = RayonDistribute(U32::new());
+        let r_z = z_multiply(&paral, &a_z, &b_z);
+        Simple::from(&Matrix::from(&r_z)).assert_approx(&expected);
+        for &cutoff in &[1, 4, 16] {
+            let r_z = strassen(&paral, &a_z, &b_z, cutoff);
+            Simple::from(&Matrix::from(&r_z)).assert_approx(&expected);
+        }
+    }
+}
@@ -1,16 +1,76 @@
 use std::iter;
 
 use faster::*;
+use rayon::prelude::*;
 use smallvec::SmallVec;
 
-use super::simple::{Matrix, Slice, SliceMut};
+use super::Element;
+use super::simple::{self, Matrix, Slice, SliceMut};
+use super::znot::Distribute;
 
-pub(crate) fn multiply_add(into: &mut SliceMut, a: &Slice, b: &Slice) {
+fn dot(row: &[Element], column: &[Element]) -> Element {
+    (row.simd_iter(f32s(0.)), column.simd_iter(f32s(0.))).zip()
+        .simd_reduce(f32s(0.0), |acc, (a, b)| acc + a * b)
+        .sum()
+}
+
+// The sum and its exact rounding error.
+fn two_sum(a: Element, b: Element) -> (Element, Element) {
+    let sum = a + b;
+    let b_part = sum - a;
+    (sum, (a - (sum - b_part)) + (b - b_part))
+}
+
+// The Dot2 algorithm (Ogita, Rump and Oishi). Both the rounding errors of the products (split
+// into halves by Dekker's trick, so the partial products are exact) and of the sums are collected
+// in each lane. The result is as if computed in twice the precision, returned as the rounded
+// value plus the remaining correction.
+fn dot_compensated(row: &[Element], column: &[Element]) -> (Element, Element) {
+    // 2^12 + 1 splits the 24 bits of f32 mantissa into two halves
+    let split = |val: f32s| {
+        let scaled = val * f32s(4097.);
+        let high = scaled - (scaled - val);
+        (high, val - high)
+    };
+    let (sum, compensation) = (row.simd_iter(f32s(0.)), column.simd_iter(f32s(0.))).zip()
+        .simd_reduce((f32s(0.0), f32s(0.0)), |(sum, compensation), (a, b)| {
+            let product = a * b;
+            let (a_hi, a_lo) = split(a);
+            let (b_hi, b_lo) = split(b);
+            let product_error =
+                ((a_hi * b_hi - product) + a_hi * b_lo + a_lo * b_hi) + a_lo * b_lo;
+            let new_sum = sum + product;
+            let b_part = new_sum - sum;
+            let sum_error = (sum - (new_sum - b_part)) + (product - b_part);
+            (new_sum, compensation + (product_error + sum_error))
+        });
+    let (sum, error) = sum.scalar_reduce((0., 0.), |(sum, error), lane| {
+        let (sum, lane_error) = two_sum(sum, lane);
+        (sum, error + lane_error)
+    });
+    (sum, error + compensation.sum())
+}
+
+// The products are computed and summed up in f64, so rounding to f32 happens only at the very end.
+fn dot_mixed(row: &[Element], column: &[Element]) -> f64 {
+    (row.simd_iter(f32s(0.)), column.simd_iter(f32s(0.))).zip()
+        .simd_reduce(f64s(0.0), |acc, (a, b)| {
+            let (a_lo, a_hi) = a.upcast();
+            let (b_lo, b_hi) = b.upcast();
+            acc + a_lo * b_lo + a_hi * b_hi
+        })
+        .sum()
+}
+
+// Calls the update with the position in the result, the row of a and the column of b, for each
+// element of the product.
+fn for_each_dot<U>(a: &Slice, b: &Slice, mut update: U)
+where
+    U: FnMut(usize, usize, &[Element], &[Element]),
+{
     assert_eq!(a.width, b.height);
-    assert_eq!(a.height, into.height);
-    assert_eq!(b.width, into.width);
 
-    let h = into.height;
+    let h = a.height;
     let l = a.width;
 
     let pads = iter::repeat(f32s(0.))
@@ -28,13 +88,174 @@ pub(crate) fn multiply_add(into: &mut SliceMut, a: &Slice, b: &Slice) {
         column.scalar_fill(&mut column_data);
         for y in 0..h {
             let row = &a.content[y * l .. (y + 1) * l];
-            into[(x, y)] += (row.simd_iter(f32s(0.)), column_data.simd_iter(f32s(0.))).zip()
-                .simd_reduce(f32s(0.0), |acc, (a, b)| acc + a * b)
-                .sum();
+            update(x, y, row, &column_data);
+        }
+    }
+}
+
+// The update gets the old value of the result element, the row of a and the column of b.
+fn multiply_add_by<U>(into: &mut SliceMut, a: &Slice, b: &Slice, update: U)
+where
+    U: Fn(Element, &[Element], &[Element]) -> Element,
+{
+    assert_eq!(a.height, into.height);
+    assert_eq!(b.width, into.width);
+
+    for_each_dot(a, b, |x, y, row, column| into[(x, y)] = update(into[(x, y)], row, column));
+}
+
+pub(crate) fn multiply_add(into: &mut SliceMut, a: &Slice, b: &Slice) {
+    multiply_add_by(into, a, b, |acc, row, column| acc + dot(row, column));
+}
+
+// Like for_each_dot, but on an m×k and k×n blocks of bigger matrices, with the rows the strides
+// apart. The columns of b are gathered one at a time, nothing gets padded.
+fn for_each_dot_strided<U>(
+    a: &[Element],
+    a_stride: usize,
+    b: &[Element],
+    b_stride: usize,
+    (m, k, n): (usize, usize, usize),
+    mut update: U,
+)
+where
+    U: FnMut(usize, usize, &[Element], &[Element]),
+{
+    let mut column = iter::repeat(0.0)
+        .take(k)
+        .collect::<SmallVec<[_; 512]>>();
+    for x in 0..n {
+        for (p, val) in column.iter_mut().enumerate() {
+            *val = b[p * b_stride + x];
+        }
+        for y in 0..m {
+            update(x, y, &a[y * a_stride .. y * a_stride + k], &column);
         }
     }
 }
 
+// dst = src + scale * row
+fn scaled_add(dst: &mut [Element], src: &[Element], scale: Element, row: &[Element]) {
+    (src.simd_iter(f32s(0.)), row.simd_iter(f32s(0.))).zip()
+        .simd_map(|(acc, val)| acc + f32s(scale) * val)
+        .scalar_fill(dst);
+}
+
+// into += Σ scale * row. The SIMD iterators can't update a slice in place, so the partial sums go
+// back and forth between into and a scratch buffer.
+fn add_scaled_rows<'a, R>(into: &mut [Element], rows: R)
+where
+    R: IntoIterator<Item = (Element, &'a [Element])>,
+{
+    let mut scratch = iter::repeat(0.0)
+        .take(into.len())
+        .collect::<SmallVec<[_; VEC_MUL_BLOCK]>>();
+    let mut in_scratch = false;
+    for (scale, row) in rows {
+        let row = &row[.. into.len()];
+        if in_scratch {
+            scaled_add(into, &scratch, scale, row);
+        } else {
+            scaled_add(&mut scratch, into, scale, row);
+        }
+        in_scratch = !in_scratch;
+    }
+    if in_scratch {
+        into.copy_from_slice(&scratch);
+    }
+}
+
+// r += a * b on an m×k and k×n blocks of bigger matrices, with the rows the strides apart. Each
+// row of the result is built from the scaled rows of b, all of them continuous.
+pub(crate) fn multiply_add_strided(
+    r: &mut [Element],
+    r_stride: usize,
+    a: &[Element],
+    a_stride: usize,
+    b: &[Element],
+    b_stride: usize,
+    (m, k, n): (usize, usize, usize),
+) {
+    for y in 0..m {
+        let row = &a[y * a_stride .. y * a_stride + k];
+        let rows = row
+            .iter()
+            .enumerate()
+            .map(|(p, &scale)| (scale, &b[p * b_stride .. p * b_stride + n]));
+        add_scaled_rows(&mut r[y * r_stride .. y * r_stride + n], rows);
+    }
+}
+
+fn add_dot_compensated(acc: Element, row: &[Element], column: &[Element]) -> Element {
+    let (dot, correction) = dot_compensated(row, column);
+    let (sum, error) = two_sum(acc, dot);
+    sum + (error + correction)
+}
+
+pub(crate) fn multiply_add_compensated(into: &mut SliceMut, a: &Slice, b: &Slice) {
+    multiply_add_by(into, a, b, add_dot_compensated);
+}
+
+// Like multiply_add_strided, but compensated.
+pub(crate) fn multiply_add_compensated_strided(
+    r: &mut [Element],
+    r_stride: usize,
+    a: &[Element],
+    a_stride: usize,
+    b: &[Element],
+    b_stride: usize,
+    shape: (usize, usize, usize),
+) {
+    for_each_dot_strided(a, a_stride, b, b_stride, shape, |x, y, row, column| {
+        let idx = y * r_stride + x;
+        r[idx] = add_dot_compensated(r[idx], row, column);
+    });
+}
+
+// Like multiply_add_compensated, but the result is kept as the (unrounded) pairs of the sum and
+// its correction. Adding into them loses nothing, unlike rounding to a single f32 each time.
+pub(crate) fn multiply_add_split(into: &mut [(Element, Element)], a: &Slice, b: &Slice) {
+    assert_eq!(a.height * b.width, into.len());
+    let w = b.width;
+    for_each_dot(a, b, |x, y, row, column| {
+        let (dot, correction) = dot_compensated(row, column);
+        let (sum, old_correction) = into[x + w * y];
+        let (sum, error) = two_sum(sum, dot);
+        into[x + w * y] = (sum, old_correction + (error + correction));
+    });
+}
+
+fn add_dot_mixed(acc: Element, row: &[Element], column: &[Element]) -> Element {
+    (f64::from(acc) + dot_mixed(row, column)) as Element
+}
+
+pub(crate) fn multiply_add_mixed(into: &mut SliceMut, a: &Slice, b: &Slice) {
+    multiply_add_by(into, a, b, add_dot_mixed);
+}
+
+// Like multiply_add_strided, but accumulating in f64.
+pub(crate) fn multiply_add_mixed_strided(
+    r: &mut [Element],
+    r_stride: usize,
+    a: &[Element],
+    a_stride: usize,
+    b: &[Element],
+    b_stride: usize,
+    shape: (usize, usize, usize),
+) {
+    for_each_dot_strided(a, a_stride, b, b_stride, shape, |x, y, row, column| {
+        let idx = y * r_stride + x;
+        r[idx] = add_dot_mixed(r[idx], row, column);
+    });
+}
+
+// Like multiply_add_mixed, but the result itself is kept in f64, so it is not rounded at all.
+pub(crate) fn multiply_add_wide(into: &mut [f64], a: &Slice, b: &Slice) {
+    assert_eq!(a.height * b.width, into.len());
+    let w = b.width;
+    for_each_dot(a, b, |x, y, row, column| into[x + w * y] += dot_mixed(row, column));
+}
+
 pub fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
     let mut result = Matrix::sized(b.width(), a.height());
 
@@ -43,11 +264,119 @@ pub fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
     result
 }
 
+/// Like [`multiply`](fn.multiply.html), but bands of the result rows are handed to the `Dist`.
+///
+/// Each band transposes its own copy of b, which is cheap compared to the multiplication itself.
+pub fn multiply_paral<Dist: Distribute>(dist: &Dist, a: &Matrix, b: &Matrix) -> Matrix {
+    simple::multiply_bands(dist, a, b, multiply_add)
+}
+
+/// Like [`multiply`](fn.multiply.html), but uses compensated summation (and compensates for the
+/// rounding of the products too).
+///
+/// This is slower, but the result stays accurate even with long rows.
+pub fn multiply_compensated(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut result = Matrix::sized(b.width(), a.height());
+
+    multiply_add_compensated(&mut result.slice_mut(), &a.slice(), &b.slice());
+
+    result
+}
+
+/// Like [`multiply`](fn.multiply.html), but accumulates in f64.
+///
+/// The inputs and the result stay in f32, but each element of the result is rounded only once.
+pub fn multiply_mixed(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut result = Matrix::sized(b.width(), a.height());
+
+    multiply_add_mixed(&mut result.slice_mut(), &a.slice(), &b.slice());
+
+    result
+}
+
+// Matrices with at least this many elements are multiplied by vectors in parallel.
+const PARALLEL_VEC: usize = 1 << 16;
+// Number of columns of the result handled by one task in the vector-matrix product.
+const VEC_MUL_BLOCK: usize = 1024;
+
+// Sums the rows of a matrix (restricted to the columns starting at x), each scaled by the
+// corresponding element of the vector.
+fn scaled_rows(into: &mut [Element], content: &[Element], width: usize, x: usize, v: &[Element]) {
+    for val in into.iter_mut() {
+        *val = 0.;
+    }
+    // into += scale * row, both continuous, so the columns of a are never gathered
+    let rows = v.iter().zip(content.chunks(width)).map(|(&scale, row)| (scale, &row[x ..]));
+    add_scaled_rows(into, rows);
+}
+
+impl Matrix {
+    /// The matrix-vector product (`self * v`).
+    pub fn mul_vec(&self, v: &[Element]) -> Vec<Element> {
+        let mut result = vec![0.; self.height()];
+        self.mul_vec_into(v, &mut result);
+        result
+    }
+    pub fn mul_vec_into(&self, v: &[Element], into: &mut [Element]) {
+        assert_eq!(v.len(), self.width());
+        assert_eq!(into.len(), self.height());
+
+        let w = self.width();
+        if w == 0 {
+            for val in into.iter_mut() {
+                *val = 0.;
+            }
+            return;
+        }
+
+        let content = self.slice().content;
+        if w * self.height() >= PARALLEL_VEC {
+            into.par_iter_mut()
+                .zip(content.par_chunks(w))
+                .for_each(|(r, row)| *r = dot(row, v));
+        } else {
+            for (r, row) in into.iter_mut().zip(content.chunks(w)) {
+                *r = dot(row, v);
+            }
+        }
+    }
+    /// The vector-matrix product (`v * self`).
+    pub fn vec_mul(&self, v: &[Element]) -> Vec<Element> {
+        let mut result = vec![0.; self.width()];
+        self.vec_mul_into(v, &mut result);
+        result
+    }
+    pub fn vec_mul_into(&self, v: &[Element], into: &mut [Element]) {
+        assert_eq!(v.len(), self.height());
+        assert_eq!(into.len(), self.width());
+
+        let w = self.width();
+        if w == 0 {
+            return;
+        }
+
+        let content = self.slice().content;
+        if w * self.height() >= PARALLEL_VEC {
+            into.par_chunks_mut(VEC_MUL_BLOCK)
+                .enumerate()
+                .for_each(|(i, block)| scaled_rows(block, content, w, i * VEC_MUL_BLOCK, v));
+        } else {
+            for (i, block) in into.chunks_mut(VEC_MUL_BLOCK).enumerate() {
+                scaled_rows(block, content, w, i * VEC_MUL_BLOCK, v);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use typenum::U64;
+
     use ::simple::{self, Matrix};
+    use ::simple::tests::check_shapes;
+    use ::znot::{DontDistribute, RayonDistribute};
 
     /*
      * By using SIMD vectors to sum many at once, we reorder the additions on floats. It so happens
@@ -76,6 +405,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn paral() {
+        let paral = RayonDistribute(U64::new());
+        // Around the band boundary, and an empty one
+        let extra = [(64, 5, 3), (65, 5, 3), (0, 5, 5)];
+        check_shapes(&extra, |a, b, expected| {
+            let serial = multiply(a, b);
+            serial.assert_approx(expected);
+            // The bands do the same dot products as the serial version
+            assert_eq!(serial, multiply_paral(&DontDistribute, a, b));
+            assert_eq!(serial, multiply_paral(&paral, a, b));
+        });
+    }
+
     #[test]
     fn id() {
         for size in 1..4 {
@@ -85,6 +428,61 @@ mod tests {
         }
     }
 
+    #[test]
+    fn compensated() {
+        // Long rows are where the plain summation drifts away the most, and the mixed signs make
+        // the sums cancel out
+        let l = 16384;
+        let a = Matrix::random_signed(l, 3);
+        let b = Matrix::random_signed(3, l);
+        multiply_compensated(&a, &b).assert_ulps(&a, &b, 2.);
+    }
+
+    #[test]
+    fn mixed() {
+        let l = 16384;
+        let a = Matrix::random(l, 3);
+        let b = Matrix::random(3, l);
+        let result = multiply_mixed(&a, &b);
+        for y in 0..3 {
+            for x in 0..3 {
+                let exact = (0..l)
+                    .map(|p| f64::from(a[(p, y)]) * f64::from(b[(x, p)]))
+                    .sum::<f64>();
+                let error = (f64::from(result[(x, y)]) - exact).abs();
+                // Only the final rounding is allowed
+                assert!(error <= exact * f64::from(::std::f32::EPSILON), "{}", error);
+            }
+        }
+    }
+
+    fn vec_products(w: usize, h: usize) {
+        let m = Matrix::random(w, h);
+        let col = Matrix::random(1, w);
+        let row = Matrix::random(h, 1);
+
+        let mut expected = simple::multiply(&m, &col);
+        let mut result = expected.clone();
+        result.slice_mut().content.copy_from_slice(&m.mul_vec(col.slice().content));
+        result.assert_approx(&expected);
+
+        expected = simple::multiply(&row, &m);
+        result = expected.clone();
+        result.slice_mut().content.copy_from_slice(&m.vec_mul(row.slice().content));
+        result.assert_approx(&expected);
+    }
+
+    #[test]
+    fn vec() {
+        vec_products(1, 1);
+        vec_products(3, 7);
+        vec_products(17, 2);
+        vec_products(0, 3);
+        // Big enough to go parallel
+        vec_products(300, 300);
+        vec_products(3000, 30);
+    }
+
     #[test]
     fn rect() {
         let a = Matrix::random(2, 3);
@@ -10,6 +10,11 @@ use rand::{self, Rng};
 use smallvec::SmallVec;
 
 use super::Element;
+use super::semiring::{Arithmetic, Semiring};
+use super::znot::Distribute;
+
+// Rows of the result in one task of the parallel multiplications.
+const BAND: usize = 64;
 
 pub struct Rows<'a> {
     matrix: &'a Matrix,
@@ -74,6 +79,53 @@ impl Matrix {
     }
     pub fn height(&self) -> usize { self.height }
     pub fn width(&self) -> usize { self.width }
+    // Copies out a w×h block with the top left corner at (x, y).
+    pub(crate) fn block(&self, x: usize, y: usize, w: usize, h: usize) -> Self {
+        let mut result = Self::sized(w, h);
+        for row in 0..h {
+            let start = x + self.width * (y + row);
+            result.content[w * row .. w * (row + 1)]
+                .copy_from_slice(&self.content[start .. start + w]);
+        }
+        result
+    }
+    pub(crate) fn set_block(&mut self, x: usize, y: usize, block: &Self) {
+        for (row, src) in block.rows().enumerate() {
+            let start = x + self.width * (y + row);
+            self.content[start .. start + block.width].copy_from_slice(src);
+        }
+    }
+    // Combines two matrices of the same shape element by element.
+    pub(crate) fn zip_with<F: Fn(Element, Element) -> Element>(&self, other: &Self, f: F) -> Self {
+        assert_eq!(self.width, other.width);
+        assert_eq!(self.height, other.height);
+        let content = self.content
+            .iter()
+            .zip(&other.content)
+            .map(|(&a, &b)| f(a, b))
+            .collect();
+        Self {
+            width: self.width,
+            height: self.height,
+            content,
+        }
+    }
+    pub(crate) fn swap_rows(&mut self, a: usize, b: usize) {
+        for x in 0..self.width {
+            self.content.swap(x + self.width * a, x + self.width * b);
+        }
+    }
+    // Kernels made of dot products want the columns of b continuous in memory, so they take its
+    // transposition instead.
+    pub(crate) fn transposed(&self) -> Self {
+        let mut result = Self::sized(self.height, self.width);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                result[(y, x)] = self[(x, y)];
+            }
+        }
+        result
+    }
     pub(crate) fn slice(&self) -> Slice {
         Slice {
             width: self.width,
@@ -103,39 +155,44 @@ impl IndexMut<(usize, usize)> for Matrix {
     }
 }
 
-pub(crate) struct Slice<'a> {
+pub(crate) struct Slice<'a, E: 'a = Element> {
     pub(crate) width: usize,
     pub(crate) height: usize,
-    pub(crate) content: &'a [Element],
+    pub(crate) content: &'a [E],
 }
 
-impl<'a> Index<(usize, usize)> for Slice<'a> {
-    type Output = Element;
-    fn index(&self, index: (usize, usize)) -> &Element {
+impl<'a, E> Index<(usize, usize)> for Slice<'a, E> {
+    type Output = E;
+    fn index(&self, index: (usize, usize)) -> &E {
         &self.content[index.0 + self.width * index.1]
     }
 }
 
-pub(crate) struct SliceMut<'a> {
+pub(crate) struct SliceMut<'a, E: 'a = Element> {
     pub(crate) width: usize,
     pub(crate) height: usize,
-    pub(crate) content: &'a mut [Element],
+    pub(crate) content: &'a mut [E],
 }
 
-impl<'a> Index<(usize, usize)> for SliceMut<'a> {
-    type Output = Element;
-    fn index(&self, index: (usize, usize)) -> &Element {
+impl<'a, E> Index<(usize, usize)> for SliceMut<'a, E> {
+    type Output = E;
+    fn index(&self, index: (usize, usize)) -> &E {
         &self.content[index.0 + self.width * index.1]
     }
 }
 
-impl<'a> IndexMut<(usize, usize)> for SliceMut<'a> {
-    fn index_mut(&mut self, index: (usize, usize)) -> &mut Element {
+impl<'a, E> IndexMut<(usize, usize)> for SliceMut<'a, E> {
+    fn index_mut(&mut self, index: (usize, usize)) -> &mut E {
         &mut self.content[index.0 + self.width * index.1]
     }
 }
 
-pub(crate) fn multiply_add(into: &mut SliceMut, a: &Slice, b: &Slice) {
+// The classical multiplication, with the `+` and `*` of the semiring.
+pub(crate) fn multiply_add_in<S: Semiring>(
+    into: &mut SliceMut<S::Element>,
+    a: &Slice<S::Element>,
+    b: &Slice<S::Element>,
+) {
     assert_eq!(a.width, b.height);
 
     let w = into.width;
@@ -145,12 +202,16 @@ pub(crate) fn multiply_add(into: &mut SliceMut, a: &Slice, b: &Slice) {
     for x in 0..w {
         for y in 0..h {
             for p in 0..l {
-                into[(x, y)] += a[(p, y)] * b[(x, p)];
+                into[(x, y)] = S::add(into[(x, y)], S::mul(a[(p, y)], b[(x, p)]));
             }
         }
     }
 }
 
+pub(crate) fn multiply_add(into: &mut SliceMut, a: &Slice, b: &Slice) {
+    multiply_add_in::<Arithmetic>(into, a, b);
+}
+
 pub fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
     let mut r = Matrix::sized(b.width, a.height);
 
@@ -166,6 +227,55 @@ pub fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
     r
 }
 
+// Splits the rows of the result (and the corresponding rows of a) into bands and hands them to the
+// `Dist`, each multiplied by the `mult`.
+pub(crate) fn multiply_bands<Dist, M>(dist: &Dist, a: &Matrix, b: &Matrix, mult: M) -> Matrix
+where
+    Dist: Distribute,
+    M: Fn(&mut SliceMut, &Slice, &Slice) + Sync,
+{
+    assert_eq!(a.width, b.height);
+    let mut r = Matrix::sized(b.width, a.height);
+
+    a.validate();
+    b.validate();
+    r.validate();
+
+    // Nothing to compute, and the empty rows wouldn't split into chunks
+    if r.content.is_empty() || a.width == 0 {
+        return r;
+    }
+
+    let w = r.width;
+    let l = a.width;
+    let b = b.slice();
+    let mut tasks = r.content
+        .chunks_mut(BAND * w)
+        .zip(a.content.chunks(BAND * l))
+        .collect::<Vec<_>>();
+    dist.run(a.height, &mut tasks, |&mut (ref mut r, a)| {
+        let height = a.len() / l;
+        let mut into = SliceMut {
+            width: w,
+            height,
+            content: r,
+        };
+        let a = Slice {
+            width: l,
+            height,
+            content: a,
+        };
+        mult(&mut into, &a, &b);
+    });
+
+    r
+}
+
+/// Like [`multiply`](fn.multiply.html), but bands of the result rows are handed to the `Dist`.
+pub fn multiply_paral<Dist: Distribute>(dist: &Dist, a: &Matrix, b: &Matrix) -> Matrix {
+    multiply_bands(dist, a, b, multiply_add)
+}
+
 pub fn multiply_col_cp(a: &Matrix, b: &Matrix) -> Matrix {
     let mut r = Matrix::sized(b.width, a.height);
 
@@ -201,9 +311,41 @@ pub fn multiply_col_cp(a: &Matrix, b: &Matrix) -> Matrix {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
+    use typenum::U64;
+
+    use ::znot::{DontDistribute, RayonDistribute};
+
+    // Shapes (m, k, n) of the products (a is m×k, b is k×n) all the multiplications are checked
+    // on.
+    pub(crate) const SHAPES: &[(usize, usize, usize)] = &[
+        (1, 1, 1),
+        (2, 3, 4),
+        (3, 5, 7),
+        (17, 4, 9),
+        (64, 64, 64),
+        (64, 33, 15),
+        (31, 64, 65),
+        (100, 3, 100),
+        (1, 200, 1),
+        (200, 130, 129),
+    ];
+
+    // Multiplies random matrices of the SHAPES and the extra ones by the reference multiply and
+    // passes them to check as (a, b, expected).
+    pub(crate) fn check_shapes<F>(extra: &[(usize, usize, usize)], check: F)
+    where
+        F: Fn(&Matrix, &Matrix, &Matrix),
+    {
+        for &(m, k, n) in SHAPES.iter().chain(extra) {
+            let a = Matrix::random(k, m);
+            let b = Matrix::random(n, k);
+            check(&a, &b, &multiply(&a, &b));
+        }
+    }
+
     impl Matrix {
         pub(crate) fn identity(size: usize) -> Self {
             let mut r = Self::sized(size, size);
@@ -212,6 +354,49 @@ mod tests {
             }
             r
         }
+
+        // Unlike exact comparison, this allows for the rounding errors caused by reordering of
+        // the operations.
+        pub(crate) fn assert_approx(&self, other: &Self) {
+            assert_eq!(self.width, other.width);
+            assert_eq!(self.height, other.height);
+            for (a, b) in self.content.iter().zip(&other.content) {
+                let scale = a.abs().max(b.abs()).max(1.0);
+                assert!((a - b).abs() <= scale * 1e-3, "{} != {}", a, b);
+            }
+        }
+
+        // Like assert_approx, but the tolerance is relative even to the small elements.
+        pub(crate) fn assert_relative(&self, other: &Self, tolerance: Element) {
+            assert_eq!(self.width, other.width);
+            assert_eq!(self.height, other.height);
+            for (a, b) in self.content.iter().zip(&other.content) {
+                assert!((a - b).abs() <= a.abs().max(b.abs()) * tolerance, "{} != {}", a, b);
+            }
+        }
+
+        // Elements from -5 to 5, so the sums cancel out.
+        pub(crate) fn random_signed(w: usize, h: usize) -> Self {
+            let zero = Self::sized(w, h);
+            Self::random(w, h).zip_with(&zero, |val, _| val - 5.)
+        }
+
+        // Checks this is the product of a and b, computed exactly and rounded with an error of at
+        // most this many ulps.
+        pub(crate) fn assert_ulps(&self, a: &Self, b: &Self, ulps: f64) {
+            assert_eq!(self.width, b.width);
+            assert_eq!(self.height, a.height);
+            for y in 0..self.height {
+                for x in 0..self.width {
+                    let exact = (0..a.width)
+                        .map(|p| f64::from(a[(p, y)]) * f64::from(b[(x, p)]))
+                        .sum::<f64>();
+                    let error = (f64::from(self[(x, y)]) - exact).abs();
+                    let ulp = exact.abs() * f64::from(::std::f32::EPSILON);
+                    assert!(error <= ulps * ulp, "{} != {}", self[(x, y)], exact);
+                }
+            }
+        }
     }
 
     #[test]
@@ -276,6 +461,18 @@ mod tests {
         }
     }
 
+    #[test]
+    fn paral() {
+        let paral = RayonDistribute(U64::new());
+        // Exactly one band, one row into the next, and the empty ones
+        let extra = [(BAND, 5, 3), (BAND + 1, 5, 3), (0, 5, 5), (5, 0, 5)];
+        check_shapes(&extra, |a, b, expected| {
+            // Each element is summed in the same order, so it is exact
+            assert_eq!(expected, &multiply_paral(&DontDistribute, a, b));
+            assert_eq!(expected, &multiply_paral(&paral, a, b));
+        });
+    }
+
     #[test]
     fn arbitrary() {
         let a = Matrix {
@@ -0,0 +1,198 @@
+use std::path::Path;
+
+use failure::Error;
+use rayon::prelude::*;
+
+use super::Element;
+use super::simple::Matrix;
+
+/// A sparse matrix in the compressed sparse row format.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Csr {
+    width: usize,
+    height: usize,
+    // Where each row starts in columns and values, with one extra at the end.
+    row_starts: Vec<usize>,
+    columns: Vec<usize>,
+    values: Vec<Element>,
+}
+
+impl Csr {
+    /// Elements with the absolute value of at most `threshold` are considered zero.
+    pub fn from_dense(matrix: &Matrix, threshold: Element) -> Self {
+        let mut row_starts = Vec::with_capacity(matrix.height() + 1);
+        let mut columns = Vec::new();
+        let mut values = Vec::new();
+        row_starts.push(0);
+        for row in matrix.rows() {
+            for (x, &val) in row.iter().enumerate() {
+                if val.abs() > threshold {
+                    columns.push(x);
+                    values.push(val);
+                }
+            }
+            row_starts.push(columns.len());
+        }
+        Self {
+            width: matrix.width(),
+            height: matrix.height(),
+            row_starts,
+            columns,
+            values,
+        }
+    }
+    /// Builds the matrix from (column, row, value) triplets.
+    ///
+    /// Multiple triplets for the same position are summed together.
+    pub fn from_triplets(width: usize, height: usize, triplets: &[(usize, usize, Element)])
+        -> Self
+    {
+        let mut sorted = triplets.to_vec();
+        sorted.sort_by_key(|&(x, y, _)| (y, x));
+
+        let mut rows = Vec::with_capacity(sorted.len());
+        let mut columns = Vec::with_capacity(sorted.len());
+        let mut values = Vec::with_capacity(sorted.len());
+        for (x, y, val) in sorted {
+            assert!(x < width && y < height, "Triplet ({}, {}) is outside of the matrix", x, y);
+            if rows.last() == Some(&y) && columns.last() == Some(&x) {
+                *values.last_mut().unwrap() += val;
+            } else {
+                rows.push(y);
+                columns.push(x);
+                values.push(val);
+            }
+        }
+
+        let mut row_starts = vec![0; height + 1];
+        for &y in &rows {
+            row_starts[y + 1] += 1;
+        }
+        for y in 0..height {
+            row_starts[y + 1] += row_starts[y];
+        }
+
+        Self {
+            width,
+            height,
+            row_starts,
+            columns,
+            values,
+        }
+    }
+    /// Loads a dense matrix file (as stored by the `manip` tool) and converts it.
+    pub fn load(file: &Path, threshold: Element) -> Result<Self, Error> {
+        Ok(Self::from_dense(&Matrix::load(file)?, threshold))
+    }
+    pub fn to_dense(&self) -> Matrix {
+        let mut result = Matrix::sized(self.width, self.height);
+        for y in 0..self.height {
+            let (columns, values) = self.row(y);
+            for (&x, &val) in columns.iter().zip(values) {
+                result[(x, y)] = val;
+            }
+        }
+        result
+    }
+    fn row(&self, y: usize) -> (&[usize], &[Element]) {
+        let range = self.row_starts[y] .. self.row_starts[y + 1];
+        (&self.columns[range.clone()], &self.values[range])
+    }
+    pub fn height(&self) -> usize { self.height }
+    pub fn width(&self) -> usize { self.width }
+    /// Number of the stored (non-zero) elements.
+    pub fn nnz(&self) -> usize { self.values.len() }
+}
+
+/// Multiplies a sparse matrix by a dense one.
+///
+/// The rows of the result are computed in parallel.
+pub fn multiply(a: &Csr, b: &Matrix) -> Matrix {
+    assert_eq!(a.width, b.height());
+    let w = b.width();
+    let mut result = Matrix::sized(w, a.height);
+    if w == 0 {
+        return result;
+    }
+
+    let b_content = b.slice().content;
+    result.slice_mut()
+        .content
+        .par_chunks_mut(w)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let (columns, values) = a.row(y);
+            for (&p, &val) in columns.iter().zip(values) {
+                let b_row = &b_content[p * w .. (p + 1) * w];
+                for (r, b) in row.iter_mut().zip(b_row) {
+                    *r += val * *b;
+                }
+            }
+        });
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{self, Rng};
+
+    use ::simple;
+
+    fn sparse_random(w: usize, h: usize) -> Matrix {
+        let mut result = Matrix::random(w, h);
+        let mut rng = rand::thread_rng();
+        for y in 0..h {
+            for x in 0..w {
+                if rng.gen_range(0, 10) != 0 {
+                    result[(x, y)] = 0.;
+                }
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn dense_round_trip() {
+        let m = sparse_random(13, 7);
+        let sparse = Csr::from_dense(&m, 0.);
+        assert_eq!(m, sparse.to_dense());
+        assert!(sparse.nnz() <= 13 * 7);
+    }
+
+    #[test]
+    fn threshold() {
+        let m = Matrix::random(10, 10);
+        let sparse = Csr::from_dense(&m, 5.);
+        let dense = sparse.to_dense();
+        for y in 0..10 {
+            for x in 0..10 {
+                let expected = if m[(x, y)] > 5. { m[(x, y)] } else { 0. };
+                assert_eq!(expected, dense[(x, y)]);
+            }
+        }
+    }
+
+    #[test]
+    fn triplets() {
+        let sparse = Csr::from_triplets(3, 2, &[(2, 1, 1.), (0, 0, 2.), (2, 1, 3.), (1, 0, 4.)]);
+        let mut expected = Matrix::sized(3, 2);
+        expected[(0, 0)] = 2.;
+        expected[(1, 0)] = 4.;
+        expected[(2, 1)] = 4.;
+        assert_eq!(expected, sparse.to_dense());
+        assert_eq!(3, sparse.nnz());
+    }
+
+    #[test]
+    fn mult() {
+        for &(m, k, n) in &[(1, 1, 1), (7, 13, 5), (100, 50, 30), (30, 100, 0)] {
+            let a = sparse_random(k, m);
+            let b = Matrix::random(n, k);
+            let expected = simple::multiply(&a, &b);
+            multiply(&Csr::from_dense(&a, 0.), &b).assert_approx(&expected);
+        }
+    }
+}
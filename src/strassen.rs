@@ -0,0 +1,133 @@
+use super::Element;
+use super::simd;
+use super::simple::Matrix;
+use super::znot::Distribute;
+
+fn add(a: &Matrix, b: &Matrix) -> Matrix {
+    a.zip_with(b, |a, b| a + b)
+}
+
+fn sub(a: &Matrix, b: &Matrix) -> Matrix {
+    a.zip_with(b, |a, b| a - b)
+}
+
+fn step<Dist: Distribute>(dist: &Dist, a: &Matrix, b: &Matrix, cutoff: usize) -> Matrix {
+    let (m, k, n) = (a.height(), a.width(), b.width());
+    if m <= cutoff || k <= cutoff || n <= cutoff {
+        return simd::multiply(a, b);
+    }
+
+    // If any of the dimensions is odd, the last row/column is peeled off so the rest splits into
+    // equal halves. The peeled parts are fixed up after the recursion.
+    let (m2, k2, n2) = (m / 2, k / 2, n / 2);
+
+    let a11 = a.block(0, 0, k2, m2);
+    let a12 = a.block(k2, 0, k2, m2);
+    let a21 = a.block(0, m2, k2, m2);
+    let a22 = a.block(k2, m2, k2, m2);
+    let b11 = b.block(0, 0, n2, k2);
+    let b12 = b.block(n2, 0, n2, k2);
+    let b21 = b.block(0, k2, n2, k2);
+    let b22 = b.block(n2, k2, n2, k2);
+
+    let m1l = add(&a11, &a22);
+    let m1r = add(&b11, &b22);
+    let m2l = add(&a21, &a22);
+    let m3r = sub(&b12, &b22);
+    let m4r = sub(&b21, &b11);
+    let m5l = add(&a11, &a12);
+    let m6l = sub(&a21, &a11);
+    let m6r = add(&b11, &b12);
+    let m7l = sub(&a12, &a22);
+    let m7r = add(&b21, &b22);
+
+    // Run the sub-multiplications, possibly across multiple threads
+    let mut tasks = [
+        (&m1l, &m1r, None),
+        (&m2l, &b11, None),
+        (&a11, &m3r, None),
+        (&a22, &m4r, None),
+        (&m5l, &b22, None),
+        (&m6l, &m6r, None),
+        (&m7l, &m7r, None),
+    ];
+    dist.run(m.max(k).max(n), &mut tasks, |&mut (a, b, ref mut r)| {
+        *r = Some(step(dist, a, b, cutoff));
+    });
+    let mut products = tasks.iter_mut().map(|task| task.2.take().unwrap());
+    let (p1, p2, p3, p4, p5, p6, p7) = tuplify!(7, products.next().unwrap());
+
+    // Consolidate the results
+    let mut result = Matrix::sized(n, m);
+    result.set_block(0, 0, &add(&sub(&add(&p1, &p4), &p5), &p7));
+    result.set_block(n2, 0, &add(&p3, &p5));
+    result.set_block(0, m2, &add(&p2, &p4));
+    result.set_block(n2, m2, &add(&add(&sub(&p1, &p2), &p3), &p6));
+
+    // Fix up the peeled parts
+    let (me, ke, ne) = (m2 * 2, k2 * 2, n2 * 2);
+    if ke < k {
+        // The part computed by Strassen misses a rank-1 update by the last column of a and the
+        // last row of b.
+        for y in 0..me {
+            let av = a[(k - 1, y)];
+            for x in 0..ne {
+                result[(x, y)] += av * b[(x, k - 1)];
+            }
+        }
+    }
+    if ne < n {
+        // The last column is a matrix-vector product
+        for y in 0..m {
+            result[(n - 1, y)] = (0..k).map(|p| a[(p, y)] * b[(n - 1, p)]).sum::<Element>();
+        }
+    }
+    if me < m {
+        // And the last row is a vector-matrix product (without the corner, which is already done)
+        for x in 0..ne {
+            result[(x, m - 1)] = (0..k).map(|p| a[(p, m - 1)] * b[(x, p)]).sum::<Element>();
+        }
+    }
+
+    result
+}
+
+/// Strassen multiplication of row-major matrices of arbitrary (even rectangular) shapes.
+///
+/// The recursion stops and switches to the SIMD multiplication once any of the dimensions gets to
+/// `cutoff` or below.
+pub fn multiply<Dist: Distribute>(dist: &Dist, a: &Matrix, b: &Matrix, cutoff: usize) -> Matrix {
+    assert_eq!(a.width(), b.height());
+    step(dist, a, b, cutoff.max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use typenum::U32;
+
+    use ::simple;
+    use ::simple::tests::check_shapes;
+    use ::znot::{DontDistribute, RayonDistribute};
+
+    #[test]
+    fn square() {
+        for size in 1..40 {
+            let a = Matrix::random(size, size);
+            let b = Matrix::random(size, size);
+            let expected = simple::multiply(&a, &b);
+            multiply(&DontDistribute, &a, &b, 4).assert_approx(&expected);
+        }
+    }
+
+    #[test]
+    fn rect() {
+        // At the cutoff, just above it, and odd in every dimension, so all the peeled edges get
+        // used
+        let extra = [(2, 2, 2), (3, 3, 3), (33, 17, 65)];
+        check_shapes(&extra, |a, b, expected| {
+            multiply(&RayonDistribute(U32::new()), a, b, 2).assert_approx(expected);
+        });
+    }
+}
@@ -0,0 +1,117 @@
+use faster::*;
+
+use super::Element;
+use super::simple::{Matrix, Slice};
+use super::znot::Distribute;
+
+/// Sizes of the tiles.
+///
+/// The `i` is the number of rows of the result (and `a`) in a tile, `j` the number of columns of
+/// the result (and `b`) and `k` the length of the summed dimension.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Tiles {
+    pub i: usize,
+    pub j: usize,
+    pub k: usize,
+}
+
+// Start and end of each tile along a dimension of the given length.
+fn spans(len: usize, tile: usize) -> impl Iterator<Item = (usize, usize)> {
+    (0..(len + tile - 1) / tile).map(move |t| (t * tile, ((t + 1) * tile).min(len)))
+}
+
+// Computes one band of rows of the result, from the corresponding rows of a and the whole b
+// (transposed).
+fn band(r: &mut [Element], a: &[Element], bt: &Slice, tiles_k: usize, tiles_j: usize) {
+    let l = bt.width;
+    let w = bt.height;
+    let h = r.len() / w;
+
+    for (j0, j1) in spans(w, tiles_j) {
+        for (k0, k1) in spans(l, tiles_k) {
+            for y in 0..h {
+                let row = &a[y * l + k0 .. y * l + k1];
+                for x in j0..j1 {
+                    let column = &bt.content[x * l + k0 .. x * l + k1];
+                    r[y * w + x] += (row.simd_iter(f32s(0.)), column.simd_iter(f32s(0.))).zip()
+                        .simd_reduce(f32s(0.0), |acc, (a, b)| acc + a * b)
+                        .sum();
+                }
+            }
+        }
+    }
+}
+
+/// Multiplication with the loops split into tiles.
+///
+/// Bands of `tiles.i` rows of the result are independent and are handed to the `Dist`.
+pub fn multiply<Dist: Distribute>(dist: &Dist, a: &Matrix, b: &Matrix, tiles: Tiles) -> Matrix {
+    assert_eq!(a.width(), b.height());
+    assert!(tiles.i > 0 && tiles.j > 0 && tiles.k > 0, "Tiles must not be empty");
+
+    let mut result = Matrix::sized(b.width(), a.height());
+    let w = b.width();
+    let l = a.width();
+    if w == 0 || l == 0 || a.height() == 0 {
+        return result;
+    }
+
+    let bt = b.transposed();
+    let bt = bt.slice();
+    let a_content = a.slice().content;
+    let r = result.slice_mut();
+    let mut tasks = r.content
+        .chunks_mut(tiles.i * w)
+        .zip(a_content.chunks(tiles.i * l))
+        .collect::<Vec<_>>();
+    dist.run(a.height(), &mut tasks, |&mut (ref mut r, a)| {
+        band(r, a, &bt, tiles.k, tiles.j);
+    });
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use typenum::U1;
+
+    use ::simple;
+    use ::simple::tests::check_shapes;
+    use ::znot::{DontDistribute, RayonDistribute};
+
+    #[test]
+    fn shapes() {
+        let tiles = [
+            Tiles { i: 1, j: 1, k: 1 },
+            Tiles { i: 2, j: 3, k: 5 },
+            Tiles { i: 16, j: 16, k: 16 },
+            Tiles { i: 64, j: 8, k: 3 },
+        ];
+        check_shapes(&[(0, 5, 5), (5, 0, 5), (5, 5, 0)], |a, b, expected| {
+            for &t in &tiles {
+                multiply(&DontDistribute, a, b, t).assert_approx(expected);
+                multiply(&RayonDistribute(U1::new()), a, b, t).assert_approx(expected);
+            }
+        });
+    }
+
+    #[test]
+    fn tile_larger_than_matrix() {
+        // In all the dimensions at once and in each one alone, so there's a single tile along it
+        let tiles = [
+            Tiles { i: 1000, j: 1000, k: 1000 },
+            Tiles { i: 1000, j: 2, k: 3 },
+            Tiles { i: 2, j: 1000, k: 3 },
+            Tiles { i: 2, j: 3, k: 1000 },
+        ];
+        let a = Matrix::random(9, 7);
+        let b = Matrix::random(5, 9);
+        let expected = simple::multiply(&a, &b);
+        for &t in &tiles {
+            multiply(&DontDistribute, &a, &b, t).assert_approx(&expected);
+            multiply(&RayonDistribute(U1::new()), &a, &b, t).assert_approx(&expected);
+        }
+    }
+}
@@ -1,8 +1,19 @@
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crossbeam_utils::scoped;
+use failure::Error;
+
 use faster::prelude::*;
+use rayon::ThreadPool;
 use rayon::prelude::*;
 use typenum::Unsigned;
 
 use super::Element;
+use super::lu::{self, Singular};
+use super::numa::{self, Pinning};
+use super::semiring::{Arithmetic, Ring, Semiring};
 use super::simple::{self, Matrix as Simple, Slice, SliceMut};
 use super::simd;
 
@@ -13,115 +24,378 @@ pub struct Matrix<Frag: Unsigned> {
     content: Vec<Element>,
 }
 
-impl<'a, Frag: Unsigned + Default> From<&'a Simple> for Matrix<Frag> {
-    fn from(matrix: &'a Simple) -> Self {
-        fn convert(
-            matrix: &Simple,
-            content: &mut Vec<Element>,
-            x: usize,
-            y: usize,
-            s: usize,
-            frag: usize
-        ) {
-            if s == frag {
-                for j in 0..frag {
-                    for i in 0..frag {
-                        content.push(matrix[(i + x, j + y)]);
-                    }
+// Walks a square matrix in the Z-order, reading the elements by `get` (with the column and row)
+// and passing them to `put`.
+fn z_walk<E, G, P>(get: &G, put: &mut P, x: usize, y: usize, s: usize, frag: usize)
+where
+    G: Fn(usize, usize) -> E,
+    P: FnMut(E),
+{
+    if s == frag {
+        for j in 0..frag {
+            for i in 0..frag {
+                put(get(i + x, j + y));
+            }
+        }
+    } else {
+        let s = s / 2;
+        z_walk(get, put, x, y, s, frag);
+        z_walk(get, put, x + s, y, s, frag);
+        z_walk(get, put, x, y + s, s, frag);
+        z_walk(get, put, x + s, y + s, s, frag);
+    }
+}
+
+fn check_size(size: usize, frag: usize) {
+    assert!(size % frag == 0, "Matrix size must be multiple of {}", frag);
+    assert_eq!((size / frag).count_ones(), 1, "Matrix size must be power of 2");
+}
+
+// Reorders a square matrix into the Z-order, reading its elements by the `get` callback (with
+// the column and row).
+pub(crate) fn z_order<E, G>(size: usize, frag: usize, get: G) -> Vec<E>
+where
+    G: Fn(usize, usize) -> E,
+{
+    check_size(size, frag);
+
+    let mut content = Vec::with_capacity(size * size);
+    z_walk(&get, &mut |e| content.push(e), 0, 0, size, frag);
+    content
+}
+
+// Like z_order, but writes into an existing buffer.
+pub(crate) fn z_order_into<E, G>(content: &mut [E], size: usize, frag: usize, get: G)
+where
+    G: Fn(usize, usize) -> E,
+{
+    check_size(size, frag);
+    assert_eq!(content.len(), size * size);
+
+    let mut pos = 0;
+    z_walk(&get, &mut |e| {
+        content[pos] = e;
+        pos += 1;
+    }, 0, 0, size, frag);
+}
+
+// The opposite of z_order, passes each element to the `set` callback with its column and row.
+pub(crate) fn row_major<E, S>(content: &[E], size: usize, frag: usize, mut set: S)
+where
+    E: Copy,
+    S: FnMut(usize, usize, E),
+{
+    fn convert<E: Copy, S: FnMut(usize, usize, E)>(
+        content: &[E],
+        set: &mut S,
+        x: usize,
+        y: usize,
+        s: usize,
+        frag: usize,
+        pos: &mut usize,
+    ) {
+        if s == frag {
+            for j in 0..frag {
+                for i in 0..frag {
+                    set(i + x, j + y, content[*pos]);
+                    *pos += 1;
                 }
-            } else {
-                let s = s / 2;
-                convert(matrix, content, x, y, s, frag);
-                convert(matrix, content, x + s, y, s, frag);
-                convert(matrix, content, x, y + s, s, frag);
-                convert(matrix, content, x + s, y + s, s, frag);
             }
+        } else {
+            let s = s / 2;
+            convert(content, set, x, y, s, frag, pos);
+            convert(content, set, x + s, y, s, frag, pos);
+            convert(content, set, x, y + s, s, frag, pos);
+            convert(content, set, x + s, y + s, s, frag, pos);
+        }
+    }
+    convert(content, &mut set, 0, 0, size, frag, &mut 0);
+}
+
+impl<Frag: Unsigned + Default> Matrix<Frag> {
+    // The content must already be in the Z-order.
+    pub(crate) fn from_content(size: usize, content: Vec<Element>) -> Self {
+        check_size(size, Frag::USIZE);
+        assert_eq!(content.len(), size * size);
+        Self {
+            _frag: Frag::default(),
+            size,
+            content,
         }
+    }
+}
 
+impl<'a, Frag: Unsigned + Default> From<&'a Simple> for Matrix<Frag> {
+    fn from(matrix: &'a Simple) -> Self {
         let size = matrix.width();
 
         assert_eq!(matrix.width(), matrix.height(), "We support only square matrices");
-        assert!(size % Frag::USIZE == 0, "Matrix size must be multiple of {}", Frag::USIZE);
-        assert_eq!((size / Frag::USIZE).count_ones(), 1, "Matrix size must be power of 2");
 
-        let mut content = Vec::with_capacity(size * size);
-        convert(matrix, &mut content, 0, 0, size, Frag::USIZE);
         Self {
             _frag: Frag::default(),
             size,
-            content,
+            content: z_order(size, Frag::USIZE, |x, y| matrix[(x, y)]),
         }
     }
 }
 
 impl<'a, Frag: Unsigned> From<&'a Matrix<Frag>> for Simple {
     fn from(matrix: &'a Matrix<Frag>) -> Self {
-        fn convert<Frag: Unsigned>(
-            matrix: &Matrix<Frag>,
-            result: &mut Simple,
-            x: usize,
-            y: usize,
-            s: usize,
-            pos: &mut usize,
-        ) {
-            if s == Frag::USIZE {
-                for j in 0..Frag::USIZE {
-                    for i in 0..Frag::USIZE {
-                        result[(i + x, j + y)] = matrix.content[*pos];
-                        *pos += 1;
-                    }
-                }
-            } else {
-                let s = s / 2;
-                convert(matrix, result, x, y, s, pos);
-                convert(matrix, result, x + s, y, s, pos);
-                convert(matrix, result, x, y + s, s, pos);
-                convert(matrix, result, x + s, y + s, s, pos);
-            }
-        }
         let mut result = Simple::sized(matrix.size, matrix.size);
-        convert(matrix, &mut result, 0, 0, matrix.size, &mut 0);
+        row_major(&matrix.content, matrix.size, Frag::USIZE, |x, y, val| result[(x, y)] = val);
         result
     }
 }
 
-pub trait Distribute {
-    fn run<I: Send, F: Fn(&mut I) + Send + Sync>(size: usize, tasks: &mut [I], f: F);
+/// Decides how the independent tasks of a multiplication run.
+///
+/// The `size` is the size of the (sub)matrices the tasks work on, so small ones can be kept on
+/// the current thread.
+pub trait Distribute: Sync {
+    fn run<I: Send, F: Fn(&mut I) + Send + Sync>(&self, size: usize, tasks: &mut [I], f: F);
 }
 
+#[derive(Clone, Copy, Debug, Default)]
 pub struct DontDistribute;
 
 impl Distribute for DontDistribute {
-    fn run<I: Send, F: Fn(&mut I) + Send + Sync>(_: usize, tasks: &mut [I], f: F) {
+    fn run<I: Send, F: Fn(&mut I) + Send + Sync>(&self, _: usize, tasks: &mut [I], f: F) {
         for task in tasks {
             f(task);
         }
     }
 }
 
-pub struct RayonDistribute<Limit: Unsigned>(pub Limit);
+/// The size from which the distributors run the tasks in parallel.
+///
+/// It is either known at compile time (any of the typenum unsigned numbers, which costs nothing),
+/// or set at runtime by the [`Cutoff`](struct.Cutoff.html).
+pub trait Threshold: Sync {
+    fn threshold(&self) -> usize;
+}
+
+impl<U: Unsigned + Sync> Threshold for U {
+    fn threshold(&self) -> usize {
+        U::USIZE
+    }
+}
+
+/// A threshold decided at runtime (eg. loaded from a config file or found by measuring).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Cutoff(pub usize);
+
+impl Threshold for Cutoff {
+    fn threshold(&self) -> usize {
+        self.0
+    }
+}
 
-impl<Limit: Unsigned> Distribute for RayonDistribute<Limit> {
-    fn run<I: Send, F: Fn(&mut I) + Send + Sync>(size: usize, tasks: &mut [I], f: F) {
-        if size >= Limit::USIZE {
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RayonDistribute<Limit: Threshold>(pub Limit);
+
+impl<Limit: Threshold> Distribute for RayonDistribute<Limit> {
+    fn run<I: Send, F: Fn(&mut I) + Send + Sync>(&self, size: usize, tasks: &mut [I], f: F) {
+        if size >= self.0.threshold() {
             tasks
                 .into_par_iter()
                 .for_each(f);
         } else {
-            DontDistribute::run(size, tasks, f);
+            DontDistribute.run(size, tasks, f);
+        }
+    }
+}
+
+/// Like [`RayonDistribute`](struct.RayonDistribute.html), but runs the tasks in the given thread
+/// pool instead of the global one.
+pub struct PoolDistribute<Limit: Threshold> {
+    pool: ThreadPool,
+    limit: Limit,
+}
+
+impl<Limit: Threshold + Default> PoolDistribute<Limit> {
+    pub fn new(pool: ThreadPool) -> Self {
+        Self::with_limit(pool, Limit::default())
+    }
+}
+
+impl<Limit: Threshold> PoolDistribute<Limit> {
+    pub fn with_limit(pool: ThreadPool, limit: Limit) -> Self {
+        Self {
+            pool,
+            limit,
+        }
+    }
+    /// Runs the tasks in a new pool, with the workers pinned to the CPUs as the `pinning` says.
+    pub fn pinned(pinning: Pinning, limit: Limit) -> Result<Self, Error> {
+        Ok(Self::with_limit(pinning.pool(&[])?, limit))
+    }
+    pub fn pool(&self) -> &ThreadPool {
+        &self.pool
+    }
+}
+
+impl<Limit: Threshold> Distribute for PoolDistribute<Limit> {
+    fn run<I: Send, F: Fn(&mut I) + Send + Sync>(&self, size: usize, tasks: &mut [I], f: F) {
+        if size >= self.limit.threshold() {
+            // If we already are inside the pool (on the lower levels), this just runs it.
+            self.pool.install(|| {
+                tasks
+                    .into_par_iter()
+                    .for_each(f);
+            });
+        } else {
+            DontDistribute.run(size, tasks, f);
+        }
+    }
+}
+
+// A claim on one of the thread slots of the budget, returned when dropped.
+struct Worker<'a> {
+    slot: usize,
+    taken: &'a AtomicBool,
+}
+
+impl<'a> Worker<'a> {
+    fn hire(slots: &'a [AtomicBool]) -> Option<Self> {
+        slots
+            .iter()
+            .enumerate()
+            .find(|&(_, taken)| {
+                taken.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+            })
+            .map(|(slot, taken)| Worker { slot, taken })
+    }
+}
+
+impl<'a> Drop for Worker<'a> {
+    fn drop(&mut self) {
+        self.taken.store(false, Ordering::Release);
+    }
+}
+
+/// Distributes the tasks to scoped threads, but never runs more than `threads` of them at once.
+///
+/// The threads are counted across everything using this instance or its clones (including the
+/// nested levels of the recursion and concurrent multiplications), so this is a hard cap on top
+/// of the calling threads. Other instances have budgets of their own.
+///
+/// Each call hires as many threads as there are tasks (minus one) and the budget allows. These
+/// are fresh OS threads, spawned for the call. They and the calling thread then take the tasks
+/// one by one until all are done, so a thread is not spawned for each task. When no thread is
+/// free, the calling thread does all the tasks itself. Matrices smaller than the `limit` are not
+/// distributed at all.
+///
+/// With [`with_pinning`](#method.with_pinning), each of the `threads` slots of the budget gets a
+/// CPU of its own (the slots wrap around if there are more of them than CPUs) and a hired thread
+/// runs only there.
+#[derive(Clone, Debug)]
+pub struct ThreadBudgetDistribute<Limit: Threshold> {
+    limit: Limit,
+    slots: Arc<Vec<AtomicBool>>,
+    cpus: Arc<Vec<usize>>,
+}
+
+impl<Limit: Threshold> ThreadBudgetDistribute<Limit> {
+    pub fn new(threads: usize, limit: Limit) -> Self {
+        Self {
+            limit,
+            slots: Arc::new((0..threads).map(|_| AtomicBool::new(false)).collect()),
+            cpus: Arc::new(Vec::new()),
+        }
+    }
+    /// Fails if the CPUs can't be found out or pinned to, instead of running unpinned.
+    pub fn with_pinning(threads: usize, limit: Limit, pinning: Pinning) -> Result<Self, Error> {
+        let cpus = pinning.cpus(&[])?;
+        numa::check_pinning(&cpus.iter().map(|&cpu| vec![cpu]).collect::<Vec<_>>())?;
+        Ok(Self {
+            cpus: Arc::new(cpus),
+            ..Self::new(threads, limit)
+        })
+    }
+    pub fn threads(&self) -> usize {
+        self.slots.len()
+    }
+}
+
+impl<Limit: Threshold> Distribute for ThreadBudgetDistribute<Limit> {
+    fn run<I: Send, F: Fn(&mut I) + Send + Sync>(&self, size: usize, tasks: &mut [I], f: F) {
+        if size < self.limit.threshold() || tasks.len() < 2 {
+            return DontDistribute.run(size, tasks, f);
         }
+        let helpers = tasks.len() - 1;
+        let queue = Mutex::new(tasks.iter_mut());
+        let work = || loop {
+            // Not holding the lock while running the task
+            let task = queue.lock().unwrap().next();
+            match task {
+                Some(task) => f(task),
+                None => break,
+            }
+        };
+        let work = &work;
+        scoped::scope(|scope| {
+            for _ in 0..helpers {
+                match Worker::hire(&self.slots) {
+                    Some(worker) => {
+                        let cpu = if self.cpus.is_empty() {
+                            None
+                        } else {
+                            Some(self.cpus[worker.slot % self.cpus.len()])
+                        };
+                        scope.spawn(move || {
+                            let _worker = worker;
+                            if let Some(cpu) = cpu {
+                                // Checked in with_pinning, so this doesn't fail
+                                let _ = numa::pin(&[cpu]);
+                            }
+                            work();
+                        });
+                    },
+                    None => break,
+                }
+            }
+            work();
+        });
     }
 }
 
-pub trait FragMultiplyAdd {
-    fn multiply_add(r: &mut [Element], a: &[Element], b: &[Element], size: usize);
+/// Multiplication of the fragments, with the `+` and `*` of the semiring `S`.
+///
+/// The default is the usual arithmetic on `f32`, which all the SIMD kernels provide.
+pub trait FragMultiplyAdd<S: Semiring = Arithmetic> {
+    fn multiply_add(r: &mut [S::Element], a: &[S::Element], b: &[S::Element], size: usize);
+    /// Like `multiply_add`, but on an `m`×`k` and `k`×`n` blocks of bigger row-major matrices,
+    /// with the rows the given strides apart.
+    ///
+    /// Nothing is copied or padded, so a ragged block costs only its own size. The default is the
+    /// scalar loop.
+    fn multiply_add_strided(
+        r: &mut [S::Element],
+        r_stride: usize,
+        a: &[S::Element],
+        a_stride: usize,
+        b: &[S::Element],
+        b_stride: usize,
+        (m, k, n): (usize, usize, usize),
+    ) {
+        for y in 0..m {
+            for p in 0..k {
+                let av = a[y * a_stride + p];
+                for x in 0..n {
+                    let idx = y * r_stride + x;
+                    r[idx] = S::add(r[idx], S::mul(av, b[p * b_stride + x]));
+                }
+            }
+        }
+    }
 }
 
+/// The scalar loop, available for any semiring.
 pub struct SimpleMultiplyAdd;
 
-impl FragMultiplyAdd for SimpleMultiplyAdd {
-    fn multiply_add(r: &mut [Element], a: &[Element], b: &[Element], size: usize) {
-        simple::multiply_add(
+impl<S: Semiring> FragMultiplyAdd<S> for SimpleMultiplyAdd {
+    fn multiply_add(r: &mut [S::Element], a: &[S::Element], b: &[S::Element], size: usize) {
+        simple::multiply_add_in::<S>(
             &mut SliceMut {
                 width: size,
                 height: size,
@@ -163,6 +437,98 @@ impl FragMultiplyAdd for SimdMultiplyAdd {
             },
         );
     }
+    fn multiply_add_strided(
+        r: &mut [Element],
+        r_stride: usize,
+        a: &[Element],
+        a_stride: usize,
+        b: &[Element],
+        b_stride: usize,
+        shape: (usize, usize, usize),
+    ) {
+        simd::multiply_add_strided(r, r_stride, a, a_stride, b, b_stride, shape);
+    }
+}
+
+/// Uses compensated summation in the fragments.
+///
+/// Note that the results of the fragments are still summed together in the usual way, so each
+/// fragment along k adds its rounding error. The
+/// [`multiply_compensated`](fn.multiply_compensated.html) keeps the compensation across the
+/// fragments too.
+pub struct CompensatedMultiplyAdd;
+
+impl FragMultiplyAdd for CompensatedMultiplyAdd {
+    fn multiply_add(r: &mut [Element], a: &[Element], b: &[Element], size: usize) {
+        simd::multiply_add_compensated(
+            &mut SliceMut {
+                width: size,
+                height: size,
+                content: r,
+            },
+            &Slice {
+                width: size,
+                height: size,
+                content: a,
+            },
+            &Slice {
+                width: size,
+                height: size,
+                content: b,
+            },
+        );
+    }
+    fn multiply_add_strided(
+        r: &mut [Element],
+        r_stride: usize,
+        a: &[Element],
+        a_stride: usize,
+        b: &[Element],
+        b_stride: usize,
+        shape: (usize, usize, usize),
+    ) {
+        simd::multiply_add_compensated_strided(r, r_stride, a, a_stride, b, b_stride, shape);
+    }
+}
+
+/// Accumulates the products in f64 inside the fragments.
+///
+/// The inputs stay in f32. The result is rounded once per fragment, not once for the whole
+/// product (the results of fragments are summed in f32). This makes it usable with any of the
+/// algorithms, but [`multiply_mixed`](fn.multiply_mixed.html) rounds each element only once.
+pub struct MixedMultiplyAdd;
+
+impl FragMultiplyAdd for MixedMultiplyAdd {
+    fn multiply_add(r: &mut [Element], a: &[Element], b: &[Element], size: usize) {
+        simd::multiply_add_mixed(
+            &mut SliceMut {
+                width: size,
+                height: size,
+                content: r,
+            },
+            &Slice {
+                width: size,
+                height: size,
+                content: a,
+            },
+            &Slice {
+                width: size,
+                height: size,
+                content: b,
+            },
+        );
+    }
+    fn multiply_add_strided(
+        r: &mut [Element],
+        r_stride: usize,
+        a: &[Element],
+        a_stride: usize,
+        b: &[Element],
+        b_stride: usize,
+        shape: (usize, usize, usize),
+    ) {
+        simd::multiply_add_mixed_strided(r, r_stride, a, a_stride, b, b_stride, shape);
+    }
 }
 
 macro_rules! quads {
@@ -178,7 +544,63 @@ macro_rules! quads {
     }};
 }
 
-pub fn multiply<Frag, Dist, Mult>(a: &Matrix<Frag>, b: &Matrix<Frag>) -> Matrix<Frag>
+// The classical recursion. It is generic over the element types, so other kinds of matrices can
+// share it. The leaf adds the product of two fragments into the result.
+pub(crate) fn recurse<R, A, B, Dist, Leaf>(
+    dist: &Dist,
+    r: &mut [R],
+    a: &[A],
+    b: &[B],
+    size: usize,
+    frag: usize,
+    leaf: &Leaf,
+)
+where
+    R: Send,
+    A: Sync,
+    B: Sync,
+    Dist: Distribute,
+    Leaf: Fn(&mut [R], &[A], &[B], usize) + Sync,
+{
+    if size == frag {
+        leaf(r, a, b, size);
+    } else {
+        let s = size / 2;
+        let (a11, a12, a21, a22) = quads!(a);
+        let (b11, b12, b21, b22) = quads!(b);
+        let (r11, r12, r21, r22) = quads!(mut r);
+
+        let mut tasks = [
+            (r11, a11, b11, a12, b21),
+            (r12, a11, b12, a12, b22),
+            (r21, a21, b11, a22, b21),
+            (r22, a21, b12, a22, b22),
+        ];
+        dist.run(size, &mut tasks, |&mut (ref mut r, ref a1, ref b1, ref a2, ref b2)| {
+            recurse(dist, r, a1, b1, s, frag, leaf);
+            recurse(dist, r, a2, b2, s, frag, leaf);
+        });
+    }
+}
+
+pub(crate) fn mult_add<Dist, S, Mult>(
+    dist: &Dist,
+    r: &mut [S::Element],
+    a: &[S::Element],
+    b: &[S::Element],
+    size: usize,
+    frag: usize,
+)
+where
+    Dist: Distribute,
+    S: Semiring,
+    Mult: FragMultiplyAdd<S>,
+{
+    recurse(dist, r, a, b, size, frag, &Mult::multiply_add);
+}
+
+pub fn multiply<Frag, Dist, Mult>(dist: &Dist, a: &Matrix<Frag>, b: &Matrix<Frag>)
+    -> Matrix<Frag>
 where
     Frag: Unsigned + Default,
     Dist: Distribute,
@@ -191,38 +613,167 @@ where
         content: vec![0.; a.size * a.size],
     };
 
-    fn mult_add<Dist: Distribute, Mult: FragMultiplyAdd>(
-        r: &mut [Element],
-        a: &[Element],
-        b: &[Element],
-        size: usize,
-        frag: usize,
-    ) {
-        if size == frag {
-            Mult::multiply_add(r, a, b, size);
-        } else {
-            let s = size / 2;
-            let (a11, a12, a21, a22) = quads!(a);
-            let (b11, b12, b21, b22) = quads!(b);
-            let (r11, r12, r21, r22) = quads!(mut r);
+    let r = &mut result.content;
+    mult_add::<_, Arithmetic, Mult>(dist, r, &a.content, &b.content, a.size, Frag::USIZE);
 
-            let mut tasks = [
-                (r11, a11, b11, a12, b21),
-                (r12, a11, b12, a12, b22),
-                (r21, a21, b11, a22, b21),
-                (r22, a21, b12, a22, b22),
-            ];
-            Dist::run(size, &mut tasks, |&mut (ref mut r, ref a1, ref b1, ref a2, ref b2)| {
-                mult_add::<Dist, Mult>(r, a1, b1, s, frag);
-                mult_add::<Dist, Mult>(r, a2, b2, s, frag);
-            });
-        }
+    result
+}
+
+// Like mult_add, but all 8 sub-products run at once. Half of them go into a temporary buffer,
+// which is added to the result afterwards. This goes on as long as the buffers fit into the
+// budget (in elements), the children share what's left of it.
+fn mult_add_eight<Dist: Distribute, Mult: FragMultiplyAdd>(
+    dist: &Dist,
+    r: &mut [Element],
+    a: &[Element],
+    b: &[Element],
+    size: usize,
+    frag: usize,
+    budget: usize,
+) {
+    let need = size * size;
+    if size == frag || need > budget {
+        return mult_add::<_, Arithmetic, Mult>(dist, r, a, b, size, frag);
     }
-    mult_add::<Dist, Mult>(&mut result.content, &a.content, &b.content, a.size, Frag::USIZE);
+
+    let s = size / 2;
+    let budget = (budget - need) / 8;
+    let mut buffer = vec![0.; need];
+    {
+        let (a11, a12, a21, a22) = quads!(a);
+        let (b11, b12, b21, b22) = quads!(b);
+        let (r11, r12, r21, r22) = quads!(mut r);
+        let (t11, t12, t21, t22) = quads!(mut buffer);
+
+        let mut tasks = [
+            (r11, a11, b11),
+            (t11, a12, b21),
+            (r12, a11, b12),
+            (t12, a12, b22),
+            (r21, a21, b11),
+            (t21, a22, b21),
+            (r22, a21, b12),
+            (t22, a22, b22),
+        ];
+        dist.run(size, &mut tasks, |&mut (ref mut r, a, b)| {
+            mult_add_eight::<_, Mult>(dist, r, a, b, s, frag, budget);
+        });
+    }
+
+    // The quadrants of the buffer are in the same order as the ones of the result
+    for (r, t) in r.iter_mut().zip(&buffer) {
+        *r += *t;
+    }
+}
+
+/// Like [`multiply`](fn.multiply.html), but the top levels run all 8 sub-products in parallel.
+///
+/// Half of the sub-products are written into temporary buffers and summed into the result
+/// afterwards. Levels are split this way as long as the buffers fit into `memory` bytes in total,
+/// the rest is the usual recursion. More memory therefore means more parallel tasks, which helps
+/// on machines with many cores.
+pub fn multiply_eight<Frag, Dist, Mult>(
+    dist: &Dist,
+    a: &Matrix<Frag>,
+    b: &Matrix<Frag>,
+    memory: usize,
+) -> Matrix<Frag>
+where
+    Frag: Unsigned + Default,
+    Dist: Distribute,
+    Mult: FragMultiplyAdd,
+{
+    assert_eq!(a.size, b.size);
+    let mut result = Matrix {
+        _frag: Frag::default(),
+        size: a.size,
+        content: vec![0.; a.size * a.size],
+    };
+
+    let budget = memory / mem::size_of::<Element>();
+    mult_add_eight::<_, Mult>(
+        dist,
+        &mut result.content,
+        &a.content,
+        &b.content,
+        a.size,
+        Frag::USIZE,
+        budget,
+    );
 
     result
 }
 
+/// Multiplies with the products accumulated in f64, rounding each element of the result once.
+///
+/// The inputs stay in f32, only the result is kept in f64 during the computation (so it takes
+/// twice the memory of the result).
+pub fn multiply_mixed<Frag, Dist>(dist: &Dist, a: &Matrix<Frag>, b: &Matrix<Frag>) -> Matrix<Frag>
+where
+    Frag: Unsigned + Default,
+    Dist: Distribute,
+{
+    assert_eq!(a.size, b.size);
+    let mut wide = vec![0.; a.size * a.size];
+
+    let leaf = |r: &mut [f64], a: &[Element], b: &[Element], size: usize| {
+        let a = Slice {
+            width: size,
+            height: size,
+            content: a,
+        };
+        let b = Slice {
+            width: size,
+            height: size,
+            content: b,
+        };
+        simd::multiply_add_wide(r, &a, &b);
+    };
+    recurse(dist, &mut wide, &a.content, &b.content, a.size, Frag::USIZE, &leaf);
+
+    Matrix {
+        _frag: Frag::default(),
+        size: a.size,
+        content: wide.into_iter().map(|val| val as Element).collect(),
+    }
+}
+
+/// Multiplies with compensated summation carried through the whole product.
+///
+/// Each element of the result is kept as a sum and its correction until the end, so the result
+/// is as accurate as if computed in twice the precision and then rounded (within a few ulps,
+/// unless the sum cancels out almost completely). This takes twice the memory of the result.
+pub fn multiply_compensated<Frag, Dist>(dist: &Dist, a: &Matrix<Frag>, b: &Matrix<Frag>)
+    -> Matrix<Frag>
+where
+    Frag: Unsigned + Default,
+    Dist: Distribute,
+{
+    assert_eq!(a.size, b.size);
+    let mut split = vec![(0., 0.); a.size * a.size];
+
+    let leaf = |r: &mut [(Element, Element)], a: &[Element], b: &[Element], size: usize| {
+        let a = Slice {
+            width: size,
+            height: size,
+            content: a,
+        };
+        let b = Slice {
+            width: size,
+            height: size,
+            content: b,
+        };
+        simd::multiply_add_split(r, &a, &b);
+    };
+    recurse(dist, &mut split, &a.content, &b.content, a.size, Frag::USIZE, &leaf);
+
+    Matrix {
+        _frag: Frag::default(),
+        size: a.size,
+        content: split.into_iter().map(|(sum, correction)| sum + correction).collect(),
+    }
+}
+
 macro_rules! op {
     ($res: expr => $first: ident $($op: tt $next: ident)*) => {{
         ($first.simd_iter(f32s(0.)), $($next.simd_iter(f32s(0.)),)*).zip()
@@ -237,57 +788,62 @@ macro_rules! op {
     }};
 }
 
-pub fn strassen<Frag, Dist, Mult>(a: &Matrix<Frag>, b: &Matrix<Frag>) -> Matrix<Frag>
+// buf = f(a, b), element by element
+fn combined<'a, E, F>(buf: &'a mut [E], a: &[E], b: &[E], f: F) -> &'a [E]
+where
+    F: Fn(&mut [E], &[E], &[E]),
+{
+    f(buf, a, b);
+    buf
+}
+
+// Strassen needs subtraction, so it works only over rings.
+pub(crate) fn step<Dist, S, Mult>(
+    dist: &Dist,
+    r: &mut [S::Element],
+    a: &[S::Element],
+    b: &[S::Element],
+    size: usize,
+    frag: usize,
+    cutoff: usize,
+)
 where
-    Frag: Unsigned + Default,
     Dist: Distribute,
-    Mult: FragMultiplyAdd,
+    S: Ring,
+    Mult: FragMultiplyAdd<S>,
 {
-    assert_eq!(a.size, b.size);
-    let mut result = Matrix {
-        _frag: Frag::default(),
-        size: a.size,
-        content: vec![0.; a.size * a.size],
-    };
+    if size <= cutoff {
+        // Below the cutoff, the bookkeeping of Strassen costs more than the saved multiplication
+        mult_add::<_, S, Mult>(dist, r, a, b, size, frag);
+    } else {
+        let s = size / 2;
+        let block = s * s;
+        let (a11, a12, a21, a22) = quads!(a);
+        let (b11, b12, b21, b22) = quads!(b);
+        let (r11, r12, r21, r22) = quads!(mut r);
 
-    fn step<Dist: Distribute, Mult: FragMultiplyAdd>(
-        r: &mut [Element],
-        a: &[Element],
-        b: &[Element],
-        size: usize,
-        frag: usize,
-    ) {
-        if size == frag {
-            Mult::multiply_add(r, a, b, size);
-        } else {
-            let s = size / 2;
-            let block = s * s;
-            let (a11, a12, a21, a22) = quads!(a);
-            let (b11, b12, b21, b22) = quads!(b);
-            let (r11, r12, r21, r22) = quads!(mut r);
-
-            // We need some auxiliary space (for 17 matrices ‒ or can we optimise? Can we reuse the
-            // space of the results?). Allocate it in just one chunk and split it up.
-            let mut buffer = vec![0.; 17 * block];
-            let mut bc = buffer.chunks_mut(block);
-
-            // Prepare for the smaller multiplications. These are summed/subtracted with SIMD and
-            // we don't have to care about the element orders, since both matrices have them the
-            // same.
-            let m1l = op!(bc, a11 + a22);
-            let m1r = op!(bc, b11 + b22);
-            let m2l = op!(bc, a21 + a22);
-            let m3r = op!(bc, b12 - b22);
-            let m4r = op!(bc, b21 - b11);
-            let m5l = op!(bc, a11 + a12);
-            let m6l = op!(bc, a21 - a11);
-            let m6r = op!(bc, b11 + b12);
-            let m7l = op!(bc, a21 - a11);
-            let m7r = op!(bc, b21 + b22);
-
-            // Run the sub-multiplications, possibly across multiple threads
-            let (mut m1, mut m2, mut m3, mut m4, mut m5, mut m6, mut m7) =
-                tuplify!(7, bc.next().unwrap());
+        // We need some auxiliary space (for 17 matrices and 2 partial sums ‒ or can we optimise?
+        // Can we reuse the space of the results?). Allocate it in just one chunk and split it up.
+        let mut buffer = vec![S::zero(); 19 * block];
+        let mut bc = buffer.chunks_mut(block);
+
+        // Prepare for the smaller multiplications. We don't have to care about the element
+        // orders, since both matrices have them the same.
+        let m1l = combined(bc.next().unwrap(), a11, a22, S::add_elements);
+        let m1r = combined(bc.next().unwrap(), b11, b22, S::add_elements);
+        let m2l = combined(bc.next().unwrap(), a21, a22, S::add_elements);
+        let m3r = combined(bc.next().unwrap(), b12, b22, S::sub_elements);
+        let m4r = combined(bc.next().unwrap(), b21, b11, S::sub_elements);
+        let m5l = combined(bc.next().unwrap(), a11, a12, S::add_elements);
+        let m6l = combined(bc.next().unwrap(), a21, a11, S::sub_elements);
+        let m6r = combined(bc.next().unwrap(), b11, b12, S::add_elements);
+        let m7l = combined(bc.next().unwrap(), a12, a22, S::sub_elements);
+        let m7r = combined(bc.next().unwrap(), b21, b22, S::add_elements);
+
+        // Run the sub-multiplications, possibly across multiple threads
+        let (mut m1, mut m2, mut m3, mut m4, mut m5, mut m6, mut m7) =
+            tuplify!(7, bc.next().unwrap());
+        {
             let mut tasks = [
                 (&mut m1, m1l, m1r),
                 (&mut m2, m2l, b11),
@@ -297,27 +853,406 @@ where
                 (&mut m6, m6l, m6r),
                 (&mut m7, m7l, m7r),
             ];
-            Dist::run(size, &mut tasks, |&mut (ref mut r, ref a, ref b)| {
-                step::<Dist, Mult>(r, a, b, s, frag);
+            dist.run(size, &mut tasks, |&mut (ref mut r, ref a, ref b)| {
+                step::<_, S, Mult>(dist, r, a, b, s, frag, cutoff);
             });
+        }
+
+        // Consolidate the results
+        let (x, y) = (bc.next().unwrap(), bc.next().unwrap());
+        S::add_elements(x, m1, m4);
+        S::sub_elements(y, m7, m5);
+        S::add_elements(r11, x, y);
+        S::add_elements(r12, m3, m5);
+        S::add_elements(r21, m2, m4);
+        S::sub_elements(x, m1, m2);
+        S::add_elements(y, m3, m6);
+        S::add_elements(r22, x, y);
+    }
+}
+
+pub fn strassen<Frag, Dist, Mult>(dist: &Dist, a: &Matrix<Frag>, b: &Matrix<Frag>)
+    -> Matrix<Frag>
+where
+    Frag: Unsigned + Default,
+    Dist: Distribute,
+    Mult: FragMultiplyAdd,
+{
+    hybrid_strassen::<Frag, _, Mult>(dist, a, b, Frag::USIZE)
+}
+
+/// Strassen multiplication that switches to the classical recursion once the blocks get to
+/// `cutoff` size or smaller.
+///
+/// This allows keeping the fragments small (and cache friendly) while still saving some
+/// multiplications on the top levels.
+pub fn hybrid_strassen<Frag, Dist, Mult>(
+    dist: &Dist,
+    a: &Matrix<Frag>,
+    b: &Matrix<Frag>,
+    cutoff: usize,
+) -> Matrix<Frag>
+where
+    Frag: Unsigned + Default,
+    Dist: Distribute,
+    Mult: FragMultiplyAdd,
+{
+    assert_eq!(a.size, b.size);
+    // Fragments can't be split any further, so there's no Strassen below them.
+    let cutoff = cutoff.max(Frag::USIZE);
+    let mut result = Matrix {
+        _frag: Frag::default(),
+        size: a.size,
+        content: vec![0.; a.size * a.size],
+    };
+
+    let r = &mut result.content;
+    step::<_, Arithmetic, Mult>(dist, r, &a.content, &b.content, a.size, Frag::USIZE, cutoff);
+
+    result
+}
+
+// Puts ones on the diagonal (expects r to be zeroed).
+fn identity(r: &mut [Element], size: usize, frag: usize) {
+    if size == frag {
+        for i in 0..size {
+            r[i * size + i] = 1.;
+        }
+    } else {
+        let s = size / 2;
+        let (r11, _, _, r22) = quads!(mut r);
+        identity(r11, s, frag);
+        identity(r22, s, frag);
+    }
+}
+
+// r = a * b, whatever r contained before.
+fn product_into<Dist: Distribute, Mult: FragMultiplyAdd>(
+    dist: &Dist,
+    r: &mut [Element],
+    a: &[Element],
+    b: &[Element],
+    size: usize,
+    frag: usize,
+    strassen_cutoff: Option<usize>,
+) {
+    for val in r.iter_mut() {
+        *val = 0.;
+    }
+    match strassen_cutoff {
+        Some(cutoff) => step::<_, Arithmetic, Mult>(dist, r, a, b, size, frag, cutoff.max(frag)),
+        None => mult_add::<_, Arithmetic, Mult>(dist, r, a, b, size, frag),
+    }
+}
+
+impl<Frag: Unsigned + Default> Matrix<Frag> {
+    /// Raises the matrix to the `exp`-th power by repeated squaring.
+    ///
+    /// If the `strassen_cutoff` is set, the products are done by the
+    /// [`hybrid_strassen`](fn.hybrid_strassen.html) with that cutoff. The buffers for the
+    /// intermediate results are allocated once and reused for all the products (the Strassen
+    /// multiplication still needs its own temporary space).
+    pub fn pow<Dist, Mult>(&self, dist: &Dist, exp: u64, strassen_cutoff: Option<usize>) -> Self
+    where
+        Dist: Distribute,
+        Mult: FragMultiplyAdd,
+    {
+        let size = self.size;
+        let frag = Frag::USIZE;
+        let mut result = vec![0.; size * size];
+        let mut base = self.content.clone();
+        let mut tmp = vec![0.; size * size];
+        // Multiplying by the identity would be a waste of time, we just copy the base instead.
+        let mut is_identity = true;
+        let mut exp = exp;
+        while exp > 0 {
+            if exp & 1 == 1 {
+                if is_identity {
+                    result.copy_from_slice(&base);
+                    is_identity = false;
+                } else {
+                    let cutoff = strassen_cutoff;
+                    product_into::<_, Mult>(dist, &mut tmp, &result, &base, size, frag, cutoff);
+                    mem::swap(&mut result, &mut tmp);
+                }
+            }
+            exp >>= 1;
+            if exp > 0 {
+                product_into::<_, Mult>(dist, &mut tmp, &base, &base, size, frag, strassen_cutoff);
+                mem::swap(&mut base, &mut tmp);
+            }
+        }
+        if is_identity {
+            identity(&mut result, size, frag);
+        }
 
-            // Consolidate the results
-            op!(r11 => m1 + m4 - m5 + m7);
-            op!(r12 => m3 + m5);
-            op!(r21 => m2 + m4);
-            op!(r22 => m1 - m2 + m3 + m6);
+        Matrix {
+            _frag: Frag::default(),
+            size,
+            content: result,
         }
     }
-    step::<Dist, Mult>(&mut result.content, &a.content, &b.content, a.size, Frag::USIZE);
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Triangle {
+    Lower,
+    Upper,
+}
+
+// Like recurse, but with b transposed (r += a * bᵀ). The leaf gets the transposed fragment of b.
+pub(crate) fn recurse_transposed<R, A, B, Dist, Leaf>(
+    dist: &Dist,
+    r: &mut [R],
+    a: &[A],
+    b: &[B],
+    size: usize,
+    frag: usize,
+    leaf: &Leaf,
+)
+where
+    R: Send,
+    A: Sync,
+    B: Sync,
+    Dist: Distribute,
+    Leaf: Fn(&mut [R], &[A], &[B], usize) + Sync,
+{
+    if size == frag {
+        leaf(r, a, b, size);
+    } else {
+        let s = size / 2;
+        let (a11, a12, a21, a22) = quads!(a);
+        let (b11, b12, b21, b22) = quads!(b);
+        let (r11, r12, r21, r22) = quads!(mut r);
+
+        let mut tasks = [
+            (r11, a11, b11, a12, b12),
+            (r12, a11, b21, a12, b22),
+            (r21, a21, b11, a22, b12),
+            (r22, a21, b21, a22, b22),
+        ];
+        dist.run(size, &mut tasks, |&mut (ref mut r, ref a1, ref b1, ref a2, ref b2)| {
+            recurse_transposed(dist, r, a1, b1, s, frag, leaf);
+            recurse_transposed(dist, r, a2, b2, s, frag, leaf);
+        });
+    }
+}
+
+// r += a * aᵀ, but only the given triangle. With at = aᵀ the off-diagonal blocks are plain
+// products.
+fn syrk_step<Dist: Distribute, Mult: FragMultiplyAdd>(
+    dist: &Dist,
+    r: &mut [Element],
+    a: &[Element],
+    at: &[Element],
+    size: usize,
+    frag: usize,
+    triangle: Triangle,
+) {
+    if size == frag {
+        // There are only few fragments on the diagonal, so they don't need a fast kernel.
+        for y in 0..size {
+            let (from, to) = match triangle {
+                Triangle::Lower => (0, y + 1),
+                Triangle::Upper => (y, size),
+            };
+            for x in from..to {
+                let row_y = &a[y * size .. (y + 1) * size];
+                let row_x = &a[x * size .. (x + 1) * size];
+                r[y * size + x] += row_y.iter().zip(row_x).map(|(a, b)| a * b).sum::<Element>();
+            }
+        }
+    } else {
+        let s = size / 2;
+        let (a11, a12, a21, a22) = quads!(a);
+        // at12 is a21ᵀ and at21 is a12ᵀ
+        let (at11, at12, at21, at22) = quads!(at);
+        let (r11, r12, r21, r22) = quads!(mut r);
+
+        // The diagonal blocks are again symmetric, the off-diagonal one is a full product. The
+        // diagonal tasks carry the transpositions of the blocks, the other one the right factors.
+        let off = match triangle {
+            Triangle::Lower => (r21, a21, at11, a22, at21, false),
+            Triangle::Upper => (r12, a11, at12, a12, at22, false),
+        };
+        let mut tasks = [
+            (r11, a11, at11, a12, at21, true),
+            off,
+            (r22, a21, at12, a22, at22, true),
+        ];
+        dist.run(size, &mut tasks, |&mut (ref mut r, ref a1, ref b1, ref a2, ref b2, diag)| {
+            if diag {
+                syrk_step::<_, Mult>(dist, r, a1, b1, s, frag, triangle);
+                syrk_step::<_, Mult>(dist, r, a2, b2, s, frag, triangle);
+            } else {
+                mult_add::<_, Arithmetic, Mult>(dist, r, a1, b1, s, frag);
+                mult_add::<_, Arithmetic, Mult>(dist, r, a2, b2, s, frag);
+            }
+        });
+    }
+}
+
+// dst = srcᵀ
+pub(crate) fn transpose_into<E: Copy>(dst: &mut [E], src: &[E], size: usize, frag: usize) {
+    if size == frag {
+        for y in 0..size {
+            for x in 0..size {
+                dst[y * size + x] = src[x * size + y];
+            }
+        }
+    } else {
+        let s = size / 2;
+        let (s11, s12, s21, s22) = quads!(src);
+        let (d11, d12, d21, d22) = quads!(mut dst);
+        transpose_into(d11, s11, s, frag);
+        transpose_into(d12, s21, s, frag);
+        transpose_into(d21, s12, s, frag);
+        transpose_into(d22, s22, s, frag);
+    }
+}
+
+// Copies the computed triangle into the other one.
+fn mirror(r: &mut [Element], size: usize, frag: usize, triangle: Triangle) {
+    if size == frag {
+        for y in 0..size {
+            for x in 0..y {
+                match triangle {
+                    Triangle::Lower => r[x * size + y] = r[y * size + x],
+                    Triangle::Upper => r[y * size + x] = r[x * size + y],
+                }
+            }
+        }
+    } else {
+        let s = size / 2;
+        let (r11, r12, r21, r22) = quads!(mut r);
+        mirror(r11, s, frag, triangle);
+        mirror(r22, s, frag, triangle);
+        match triangle {
+            Triangle::Lower => transpose_into(r12, r21, s, frag),
+            Triangle::Upper => transpose_into(r21, r12, s, frag),
+        }
+    }
+}
+
+/// Computes `a * aᵀ`.
+///
+/// Only the given triangle (including the diagonal) is computed, which saves about half of the
+/// work. The other triangle is either left filled with zeros or mirrored from the computed one.
+pub fn syrk<Frag, Dist, Mult>(dist: &Dist, a: &Matrix<Frag>, triangle: Triangle, mirrored: bool)
+    -> Matrix<Frag>
+where
+    Frag: Unsigned + Default,
+    Dist: Distribute,
+    Mult: FragMultiplyAdd,
+{
+    let mut result = Matrix {
+        _frag: Frag::default(),
+        size: a.size,
+        content: vec![0.; a.size * a.size],
+    };
+
+    // Transposed once here, so the off-diagonal blocks don't have to do it in each fragment
+    let mut at = vec![0.; a.size * a.size];
+    transpose_into(&mut at, &a.content, a.size, Frag::USIZE);
+    let r = &mut result.content;
+    syrk_step::<_, Mult>(dist, r, &a.content, &at, a.size, Frag::USIZE, triangle);
+    if mirrored {
+        mirror(&mut result.content, a.size, Frag::USIZE, triangle);
+    }
 
     result
 }
 
+// r = a⁻¹
+fn invert_step<Dist: Distribute, Mult: FragMultiplyAdd>(
+    dist: &Dist,
+    r: &mut [Element],
+    a: &[Element],
+    size: usize,
+    frag: usize,
+    strassen_cutoff: Option<usize>,
+) -> Result<(), Singular> {
+    if size == frag {
+        let mut leaf = Simple::sized(size, size);
+        for y in 0..size {
+            for x in 0..size {
+                leaf[(x, y)] = a[y * size + x];
+            }
+        }
+        let inverse = lu::inverse(&leaf)?;
+        for y in 0..size {
+            for x in 0..size {
+                r[y * size + x] = inverse[(x, y)];
+            }
+        }
+    } else {
+        let s = size / 2;
+        let (a11, a12, a21, a22) = quads!(a);
+        let (r11, r12, r21, r22) = quads!(mut r);
+        let mut buffer = vec![0.; 4 * s * s];
+        let (x, t1, t2, tmp) = quads!(mut buffer);
+
+        // X = A11⁻¹, T1 = A21 * X, T2 = X * A12
+        invert_step::<_, Mult>(dist, x, a11, s, frag, strassen_cutoff)?;
+        product_into::<_, Mult>(dist, t1, a21, x, s, frag, strassen_cutoff);
+        product_into::<_, Mult>(dist, t2, x, a12, s, frag, strassen_cutoff);
+
+        // The Schur complement A22 - A21 * A11⁻¹ * A12; r11 is free yet, so it holds it
+        // meanwhile.
+        product_into::<_, Mult>(dist, tmp, t1, a12, s, frag, strassen_cutoff);
+        op!(r11 => a22 - tmp);
+        invert_step::<_, Mult>(dist, r22, r11, s, frag, strassen_cutoff)?;
+
+        product_into::<_, Mult>(dist, r12, t2, r22, s, frag, strassen_cutoff);
+        product_into::<_, Mult>(dist, r21, r22, t1, s, frag, strassen_cutoff);
+        for val in r12.iter_mut().chain(r21.iter_mut()) {
+            *val = -*val;
+        }
+        product_into::<_, Mult>(dist, tmp, r12, t1, s, frag, strassen_cutoff);
+        op!(r11 => x - tmp);
+    }
+    Ok(())
+}
+
+/// Computes the inverse matrix by the block (Schur complement) formula.
+///
+/// The products are done by the classical recursion or, if `strassen_cutoff` is set, by the
+/// [`hybrid_strassen`](fn.hybrid_strassen.html) with that cutoff. The fragments on the bottom
+/// are inverted by the [`lu`](../lu/index.html) decomposition.
+///
+/// There's no pivoting across the blocks. Therefore this fails not only on singular matrices, but
+/// also when any of the top-left blocks (or their Schur complements) is singular or
+/// ill-conditioned. Diagonally dominant or positive definite matrices are fine.
+pub fn invert<Frag, Dist, Mult>(dist: &Dist, a: &Matrix<Frag>, strassen_cutoff: Option<usize>)
+    -> Result<Matrix<Frag>, Singular>
+where
+    Frag: Unsigned + Default,
+    Dist: Distribute,
+    Mult: FragMultiplyAdd,
+{
+    let mut result = Matrix {
+        _frag: Frag::default(),
+        size: a.size,
+        content: vec![0.; a.size * a.size],
+    };
+
+    let (size, cutoff) = (a.size, strassen_cutoff);
+    invert_step::<_, Mult>(dist, &mut result.content, &a.content, size, Frag::USIZE, cutoff)?;
+
+    Ok(result)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use typenum::{U1, U2, U7, U16, U32};
+    use rand::{self, Rng};
+    use rayon::ThreadPoolBuilder;
+    use typenum::{U1, U2, U4, U7, U16, U32};
+
+    fn paral() -> RayonDistribute<U32> {
+        RayonDistribute::default()
+    }
 
     fn test_tab<Frag: Unsigned + Default>() {
         for shift in 0..7 {
@@ -368,35 +1303,328 @@ mod tests {
         assert_eq!(matrix, back);
     }
 
-    /*
-     * By using SIMD vectors to sum many at once, we reorder the additions on floats. It so happens
-     * this changes the result somewhat, so we put a margin there.
-     */
-    fn approx_eq(mut a: Simple, mut b: Simple) {
-        for val in a.slice_mut().content {
-            *val = (*val / 20.0).round();
+    fn test_multi<Frag: Unsigned + Default, Mult: FragMultiplyAdd>() {
+        for shift in 0..5 {
+            let a = Simple::random(Frag::USIZE * 1 << shift, Frag::USIZE * 1 << shift);
+            let b = Simple::random(Frag::USIZE * 1 << shift, Frag::USIZE * 1 << shift);
+            let expected = simple::multiply(&a, &b);
+            let a_z = Matrix::<Frag>::from(&a);
+            let b_z = Matrix::<Frag>::from(&b);
+            // The SIMD kernels reorder the additions, so the results differ a bit
+            let r_z = multiply::<_, _, Mult>(&DontDistribute, &a_z, &b_z);
+            Simple::from(&r_z).assert_approx(&expected);
+            let ra_z = multiply::<_, _, Mult>(&paral(), &a_z, &b_z);
+            Simple::from(&ra_z).assert_approx(&expected);
+            let rs_z = strassen::<_, _, Mult>(&paral(), &a_z, &b_z);
+            Simple::from(&rs_z).assert_approx(&expected);
         }
-        for val in b.slice_mut().content {
-            *val = (*val / 20.0).round();
+    }
+
+    fn test_hybrid<Frag: Unsigned + Default, Mult: FragMultiplyAdd>() {
+        for shift in 0..5 {
+            let size = Frag::USIZE * 1 << shift;
+            let a = Simple::random(size, size);
+            let b = Simple::random(size, size);
+            let expected = simple::multiply(&a, &b);
+            let a_z = Matrix::<Frag>::from(&a);
+            let b_z = Matrix::<Frag>::from(&b);
+            for &cutoff in &[0, Frag::USIZE, Frag::USIZE * 2, size / 2, size] {
+                let r_z = hybrid_strassen::<_, _, Mult>(&paral(), &a_z, &b_z, cutoff);
+                Simple::from(&r_z).assert_approx(&expected);
+            }
         }
     }
 
-    fn test_multi<Frag: Unsigned + Default, Mult: FragMultiplyAdd>() {
+    fn test_eight<Frag: Unsigned + Default, Mult: FragMultiplyAdd>() {
         for shift in 0..5 {
-            let a = Simple::random(Frag::USIZE * 1 << shift, Frag::USIZE * 1 << shift);
-            let b = Simple::random(Frag::USIZE * 1 << shift, Frag::USIZE * 1 << shift);
+            let size = Frag::USIZE * 1 << shift;
+            let a = Simple::random(size, size);
+            let b = Simple::random(size, size);
             let expected = simple::multiply(&a, &b);
             let a_z = Matrix::<Frag>::from(&a);
             let b_z = Matrix::<Frag>::from(&b);
-            let r_z = multiply::<_, DontDistribute, Mult>(&a_z, &b_z);
-            let result = Simple::from(&r_z);
-            approx_eq(expected.clone(), result);
-            let ra_z = multiply::<_, RayonDistribute<U32>, Mult>(&a_z, &b_z);
-            let result = Simple::from(&ra_z);
-            approx_eq(expected.clone(), result);
-            let rs_z = strassen::<_, RayonDistribute<U32>, Mult>(&a_z, &b_z);
-            let result = Simple::from(&rs_z);
-            approx_eq(expected, result);
+            // Nothing, just the top level, all the levels
+            let one_level = size * size * mem::size_of::<Element>();
+            for &memory in &[0, one_level, usize::max_value()] {
+                let r_z = multiply_eight::<_, _, Mult>(&paral(), &a_z, &b_z, memory);
+                Simple::from(&r_z).assert_approx(&expected);
+                let r_z = multiply_eight::<_, _, Mult>(&DontDistribute, &a_z, &b_z, memory);
+                Simple::from(&r_z).assert_approx(&expected);
+            }
+        }
+    }
+
+    #[test]
+    fn eight_1() {
+        test_eight::<U1, SimpleMultiplyAdd>();
+    }
+
+    #[test]
+    fn eight_4_simd() {
+        test_eight::<U4, SimdMultiplyAdd>();
+    }
+
+    #[test]
+    fn hybrid_1() {
+        test_hybrid::<U1, SimpleMultiplyAdd>();
+    }
+
+    #[test]
+    fn hybrid_7() {
+        test_hybrid::<U7, SimpleMultiplyAdd>();
+    }
+
+    #[test]
+    fn hybrid_16_simd() {
+        test_hybrid::<U16, SimdMultiplyAdd>();
+    }
+
+    #[test]
+    fn hybrid_16_compensated() {
+        test_hybrid::<U16, CompensatedMultiplyAdd>();
+    }
+
+    #[test]
+    fn hybrid_16_mixed() {
+        test_hybrid::<U16, MixedMultiplyAdd>();
+    }
+
+    #[test]
+    fn compensated_across_fragments() {
+        let size = 512;
+        let a = Simple::random_signed(size, size);
+        let b = Simple::random_signed(size, size);
+        let a_z = Matrix::<U16>::from(&a);
+        let b_z = Matrix::<U16>::from(&b);
+        let r_z = multiply_compensated(&paral(), &a_z, &b_z);
+        Simple::from(&r_z).assert_ulps(&a, &b, 2.);
+    }
+
+    #[test]
+    fn mixed_rounded_once() {
+        // Long enough for the rounding in each of the 32 fragments along k to show
+        let size = 512;
+        let a = Simple::random_signed(size, size);
+        let b = Simple::random_signed(size, size);
+        let a_z = Matrix::<U16>::from(&a);
+        let b_z = Matrix::<U16>::from(&b);
+        for &cutoff in &[0, 1 << 20] {
+            let r_z = multiply_mixed(&RayonDistribute(Cutoff(cutoff)), &a_z, &b_z);
+            Simple::from(&r_z).assert_ulps(&a, &b, 1.);
+        }
+    }
+
+    fn test_syrk<Frag: Unsigned + Default, Mult: FragMultiplyAdd>() {
+        for shift in 0..5 {
+            let size = Frag::USIZE * 1 << shift;
+            let a = Simple::random(size, size);
+            let expected = simple::multiply(&a, &a.transposed());
+            let a_z = Matrix::<Frag>::from(&a);
+            for &triangle in &[Triangle::Lower, Triangle::Upper] {
+                let full = syrk::<_, _, Mult>(&paral(), &a_z, triangle, true);
+                Simple::from(&full).assert_approx(&expected);
+
+                // The other triangle stays empty
+                let half = syrk::<_, _, Mult>(&DontDistribute, &a_z, triangle, false);
+                let half = Simple::from(&half);
+                let mut masked = expected.clone();
+                for y in 0..size {
+                    for x in 0..size {
+                        let computed = match triangle {
+                            Triangle::Lower => x <= y,
+                            Triangle::Upper => x >= y,
+                        };
+                        if !computed {
+                            masked[(x, y)] = 0.;
+                        }
+                    }
+                }
+                half.assert_approx(&masked);
+            }
+        }
+    }
+
+    #[test]
+    fn syrk_1() {
+        test_syrk::<U1, SimpleMultiplyAdd>();
+    }
+
+    #[test]
+    fn syrk_7() {
+        test_syrk::<U7, SimpleMultiplyAdd>();
+    }
+
+    #[test]
+    fn syrk_16_simd() {
+        test_syrk::<U16, SimdMultiplyAdd>();
+    }
+
+    fn test_pow<Frag: Unsigned + Default>(strassen_cutoff: Option<usize>) {
+        let mut rng = rand::thread_rng();
+        for shift in 0..4 {
+            let size = Frag::USIZE * 1 << shift;
+
+            // The powers of a permutation are permutations again, so they are computed exactly.
+            let mut perm = (0..size).collect::<Vec<_>>();
+            rng.shuffle(&mut perm);
+            let mut a = Simple::sized(size, size);
+            for (x, &y) in perm.iter().enumerate() {
+                a[(x, y)] = 1.;
+            }
+            let a_z = Matrix::<Frag>::from(&a);
+            for &exp in &[0, 1, 2, 3, 7, 12, 1000, 12345] {
+                // Where each column's one moves after exp steps
+                let mut moved = (0..size).collect::<Vec<_>>();
+                for _ in 0..exp {
+                    for pos in &mut moved {
+                        *pos = perm[*pos];
+                    }
+                }
+                let mut expected = Simple::sized(size, size);
+                for (x, &y) in moved.iter().enumerate() {
+                    expected[(x, y)] = 1.;
+                }
+                let result = a_z.pow::<_, SimdMultiplyAdd>(&paral(), exp, strassen_cutoff);
+                assert_eq!(expected, Simple::from(&result));
+            }
+
+            // A stochastic matrix has stochastic powers, so they neither grow nor vanish.
+            let mut a = Simple::random(size, size);
+            for x in 0..size {
+                let sum = (0..size).map(|y| a[(x, y)]).sum::<Element>();
+                for y in 0..size {
+                    a[(x, y)] /= sum;
+                }
+            }
+            let a_z = Matrix::<Frag>::from(&a);
+            let mut expected = Simple::identity(size);
+            for exp in 0..12 {
+                let result = a_z.pow::<_, SimdMultiplyAdd>(&paral(), exp, strassen_cutoff);
+                Simple::from(&result).assert_relative(&expected, 1e-4);
+                expected = simple::multiply(&expected, &a);
+            }
+        }
+    }
+
+    #[test]
+    fn pow_1() {
+        test_pow::<U1>(None);
+    }
+
+    #[test]
+    fn pow_16() {
+        test_pow::<U16>(None);
+    }
+
+    #[test]
+    fn pow_4_strassen() {
+        test_pow::<U4>(Some(8));
+    }
+
+    fn test_invert<Frag: Unsigned + Default>(strassen_cutoff: Option<usize>) {
+        for shift in 0..5 {
+            let size = Frag::USIZE * 1 << shift;
+            // Diagonally dominant, so all the blocks are invertible
+            let mut a = Simple::random(size, size);
+            for i in 0..size {
+                a[(i, i)] += 10. * size as Element;
+            }
+            let a_z = Matrix::<Frag>::from(&a);
+            let inverse = invert::<_, _, SimdMultiplyAdd>(&paral(), &a_z, strassen_cutoff).unwrap();
+            simple::multiply(&a, &Simple::from(&inverse)).assert_approx(&Simple::identity(size));
+        }
+    }
+
+    #[test]
+    fn invert_1() {
+        test_invert::<U1>(None);
+    }
+
+    #[test]
+    fn invert_7() {
+        test_invert::<U7>(None);
+    }
+
+    #[test]
+    fn invert_4_strassen() {
+        test_invert::<U4>(Some(8));
+    }
+
+    #[test]
+    fn invert_singular() {
+        let zero = Matrix::<U4>::from(&Simple::sized(16, 16));
+        assert!(invert::<_, _, SimpleMultiplyAdd>(&DontDistribute, &zero, None).is_err());
+
+        // Invertible, but the top-left block isn't
+        let mut swap = Simple::sized(2, 2);
+        swap[(1, 0)] = 1.;
+        swap[(0, 1)] = 1.;
+        let swap = Matrix::<U1>::from(&swap);
+        assert_eq!(
+            Singular,
+            invert::<_, _, SimpleMultiplyAdd>(&DontDistribute, &swap, None).unwrap_err()
+        );
+    }
+
+    fn test_dist<Dist: Distribute>(dist: &Dist) {
+        for shift in 0..5 {
+            let size = 4 * 1 << shift;
+            let a = Simple::random(size, size);
+            let b = Simple::random(size, size);
+            let expected = simple::multiply(&a, &b);
+            let a_z = Matrix::<U4>::from(&a);
+            let b_z = Matrix::<U4>::from(&b);
+            let r_z = multiply::<_, _, SimdMultiplyAdd>(dist, &a_z, &b_z);
+            Simple::from(&r_z).assert_approx(&expected);
+            let rs_z = strassen::<_, _, SimdMultiplyAdd>(dist, &a_z, &b_z);
+            Simple::from(&rs_z).assert_approx(&expected);
+        }
+    }
+
+    #[test]
+    fn thread_budget() {
+        test_dist(&ThreadBudgetDistribute::new(2, U4::new()));
+        // Nothing to hire, everything runs on the calling thread
+        test_dist(&ThreadBudgetDistribute::new(0, U4::new()));
+    }
+
+    #[test]
+    fn thread_budget_separate() {
+        let exhausted = ThreadBudgetDistribute::new(1, U4::new());
+        let clone = exhausted.clone();
+        let worker = Worker::hire(&exhausted.slots).unwrap();
+        // The clone shares the budget, other instances don't
+        assert!(Worker::hire(&clone.slots).is_none());
+        assert!(Worker::hire(&ThreadBudgetDistribute::new(1, U4::new()).slots).is_some());
+        test_dist(&clone);
+        drop(worker);
+        assert!(Worker::hire(&exhausted.slots).is_some());
+    }
+
+    #[test]
+    fn runtime_cutoff() {
+        for &cutoff in &[0, 8, 1 << 20] {
+            test_dist(&RayonDistribute(Cutoff(cutoff)));
+        }
+    }
+
+    #[test]
+    fn pool() {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(2)
+            .thread_name(|i| format!("matmult-{}", i))
+            .build()
+            .unwrap();
+        test_dist(&PoolDistribute::with_limit(pool, Cutoff(8)));
+    }
+
+    // The CPU topology comes from the Linux sysfs
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn pinned() {
+        for &pinning in &[Pinning::Off, Pinning::Cores, Pinning::Threads] {
+            test_dist(&PoolDistribute::pinned(pinning, Cutoff(8)).unwrap());
+            test_dist(&ThreadBudgetDistribute::with_pinning(2, U4::new(), pinning).unwrap());
         }
     }
 